@@ -0,0 +1,52 @@
+// Clipboard access through the platform's copy tools, so no extra crate is needed.
+#[cfg(feature = "clipboard")]
+pub fn copy(text: &str) -> Result<(), String> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    const TOOLS: &[(&str, &[&str])] = &[
+        ("pbcopy", &[]),
+        ("wl-copy", &[]),
+        ("xclip", &["-selection", "clipboard"]),
+        ("xsel", &["--clipboard", "--input"]),
+        ("clip.exe", &[]),
+    ];
+
+    // Why the last tool that was there didn't work, e.g. xclip with no $DISPLAY.
+    let mut failure = None;
+    for (program, args) in TOOLS {
+        let mut child = match Command::new(program)
+            .args(*args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                failure = Some(format!("{program}: {e}"));
+                continue;
+            }
+        };
+        let written = child
+            .stdin
+            .take()
+            .is_some_and(|mut stdin| stdin.write_all(text.as_bytes()).is_ok());
+        match child.wait() {
+            Ok(status) if status.success() && written => return Ok(()),
+            Ok(status) if !status.success() => failure = Some(format!("{program} {status}")),
+            Ok(_) => failure = Some(format!("{program} didn't take the text")),
+            Err(e) => failure = Some(format!("{program}: {e}")),
+        }
+    }
+    Err(failure.unwrap_or_else(|| {
+        let names: Vec<&str> = TOOLS.iter().map(|(program, _)| *program).collect();
+        format!("none of {} found", names.join(", "))
+    }))
+}
+
+#[cfg(not(feature = "clipboard"))]
+pub fn copy(_text: &str) -> Result<(), String> {
+    Err("built without the clipboard feature".to_string())
+}
@@ -1,300 +1,4170 @@
 use crossterm::{
     cursor,
-    event::{self, Event, KeyCode},
+    event::{self, Event, KeyCode, KeyModifiers, MouseButton, MouseEventKind},
     execute, queue,
     style::{self, Attribute, Color},
     terminal::{self, ClearType},
 };
-use git2::{Repository, Revwalk, Sort};
-use std::collections::{HashMap, HashSet};
+use git2::{
+    BlameOptions, Branch, BranchType, Commit, Delta, Diff, DiffFormat, DiffOptions, ErrorCode, Oid,
+    Repository, RepositoryState, Revwalk, Sort,
+};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::{self, BufWriter, Write};
-use std::process::Command;
-
-fn fetch_more(repo: &Repository, revwalk: &mut Revwalk, n: usize, commits: &mut Vec<String>) {
-    let new: Vec<String> = revwalk
-        .by_ref()
-        .take(n)
-        .filter_map(|oid| {
-            let oid = oid.ok()?;
-            let commit = repo.find_commit(oid).ok()?;
-            let summary = commit.summary()?.to_string();
-            Some(format!("{} {}", &oid.to_string()[..7], summary))
-        })
-        .collect();
-    commits.extend(new);
+use std::ops::Range;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod clipboard;
+
+const USAGE: &str = "\
+Usage: git-fixup-menu [options] [<since>]
+
+Options:
+  --filter-fixup-target [<base>]  List only commits owning lines the staged changes touch,
+                                  ranked by overlap; the optional <base> is a revision
+                                  blame looks no further back than
+  --stdin                         Read `<sha> <summary>` lines (e.g. `git log --oneline`)
+                                  from stdin instead of walking history
+  --limit-to-branch[=<name>]      List only commits unique to <name> (default: the current
+                                  branch), i.e. those not reachable from its base
+  --branch-base <rev>             Base for --limit-to-branch (default: the branch's upstream,
+                                  else main or master)
+  --since <ref>, <since>          List only commits reachable from HEAD but not from <ref>
+  --mark-local[=<base>]           Draw a │ gutter beside commits not yet on <base> (default:
+                                  the current branch's upstream, else main or master)
+  -s, --squash                    Create `squash!` commits, whose message is edited into the
+                                  target's when they're folded, instead of `fixup!` ones
+  --staged-only                   List only commits that changed a file with staged changes
+  --no-merges                     Leave merge commits out of the list
+  --first-parent                  Follow only the first parent of merges, leaving out the
+                                  commits they brought in
+  --hide-fixups                   Leave out fixup!, squash! and amend! commits, which are
+                                  otherwise shown dimmed
+  --interactive-rebase            After committing, open `git rebase -i` on the target's parent
+                                  to arrange the todo list by hand (no autosquash)
+  --rebase                        After committing, run `git rebase -i --autosquash` on the
+                                  target's parent to fold the fixup in
+  --max-body-lines <n>            Cap expanded bodies at <n> lines (default 10, 0 for no cap)
+  --cache-entries <n>             Keep at most <n> loaded bodies and <n> diff previews, dropping
+                                  the least recently shown (default 256, 0 for no cap)
+  --color-sha-by-age              Shade shas from bright (newest) to dim (oldest loaded)
+  --stats                         Print a summary of the history in range instead of the menu
+  --json                          Print --stats as JSON
+  --count-only                    Print just the number of commits in range
+  --header <full|short|none>      Header above the list: title and key hints (default), just
+                                  the title, or nothing. Also set by git config fixupMenu.header
+  --target-file <path>            Preselect the newest commit that changed <path>
+  --yes                           With --target-file, create the fixup without the menu
+  --no-mouse                      Leave the mouse to the terminal, e.g. for selecting text,
+                                  instead of clicking and scrolling the list
+  --confirm                       Ask y/n before leaving the menu to create the fixup
+  --resume                        Start on the commit that was selected when the menu last
+                                  closed in this repository. Also set by git config
+                                  fixupMenu.resume
+  --window <n>                    Keep only about <n> commit rows in memory, re-reading the
+                                  rest when scrolled back to. Walked commit ids are still
+                                  kept, so memory grows by 20 bytes per commit instead of a row
+  --limit <n>                     Stop walking history after <n> commits (default: no limit)
+  --editor-message-template <file>
+                                  Seed the editor with <file> for fixup commits that open one,
+                                  e.g. squash! (default: git config commit.template)
+  --pin[=<ref>]                   Keep <ref> (default: HEAD) as a row above the list that never
+                                  scrolls away. Also set by git config fixupMenu.pin
+  --previewer <cmd>               Pipe `git show <sha>` through <cmd> (e.g. delta) for the
+                                  preview pane, showing its colors as they are. Also set by
+                                  git config fixupMenu.previewer
+  --log-file <path>               Append a JSON line per menu redraw with the key read, the
+                                  terminal size, and the selected and scroll rows
+  --allow-empty                   Create the fixup even with nothing staged, passing
+                                  --allow-empty on to git commit
+  --print                         Print the full sha of the chosen commit (one per line when
+                                  several are marked) instead of committing anything
+  --graph                         Draw the branch lines beside the list, as git log --graph
+                                  does. Implies --topo-order
+  --topo-order                    List no commit before its children, at the cost of walking
+                                  the whole history before the menu opens. Otherwise commits
+                                  come in git log's default order, newest first
+  --wrap                          Wrap long summaries and body lines onto extra rows instead
+                                  of cutting them off at the screen edge
+  --no-color                      Use the terminal's own colors throughout, keeping only the
+                                  selection highlight. Also set by NO_COLOR or color.ui=never
+  --selection-style <style>       Highlight the selected row with reverse (default), bold,
+                                  bar, or bg:<color> such as bg:dark_blue
+  -h, --help                      Show this help
+
+Keys:
+  ↑/↓ k/j      move; a count first, as in 5j, moves that many rows
+  →/← l/h      expand/collapse body
+  click/wheel  select a commit / move (unless --no-mouse)
+  PgUp/PgDn    move a page          Ctrl-u/d move half a page
+  g/G Home/End first/last commit, walking the whole history for G
+  e            show the full body, J/K scrolling it if it's too long for the screen
+  Enter        create the fixup
+  L Shift-→    expand, collapsing every other body
+  p Tab        show the selected commit's diff below the list; J/K scroll it
+  s            show full shas instead of the first 7 characters
+  y / Y        copy the full sha / the full commit message
+  a / r        create an `amend!` fixup (changes and message) or a reword (message only)
+  .            fix up HEAD straight away
+  Space        mark a commit; Enter then stages and fixes up each marked commit in turn
+  :            jump to the commit whose sha starts with what's typed next; Enter or Esc to stop
+  /            search summaries (and expanded bodies); Enter keeps the filter, Esc drops it
+  q Esc        quit; Esc clears a kept search first
+";
+
+const DEFAULT_BODY_LINES: usize = 10;
+const DEFAULT_CACHE_ENTRIES: usize = 256;
+
+// Errors that end the program; `main` prints them as a single line.
+type Result<T, E = Box<dyn std::error::Error>> = std::result::Result<T, E>;
+
+#[derive(Default)]
+struct Options {
+    // `Some(None)` ranks against the whole history, `Some(Some(base))` stops blame at `base`.
+    filter_fixup_target: Option<Option<String>>,
+    stdin: bool,
+    max_body_lines: Option<usize>,
+    cache_entries: Option<usize>,
+    interactive_rebase: bool,
+    // --rebase: like `interactive_rebase`, but with the fixups already in place.
+    autosquash_rebase: bool,
+    selection_style: SelectionStyle,
+    // `Some(None)` limits to the current branch.
+    limit_to_branch: Option<Option<String>>,
+    branch_base: Option<String>,
+    color_sha_by_age: bool,
+    stats: Option<StatsFormat>,
+    count_only: bool,
+    header: Option<HeaderStyle>,
+    target_file: Option<String>,
+    yes: bool,
+    window: Option<usize>,
+    // Most commits the walk will ever load.
+    limit: Option<usize>,
+    message_template: Option<PathBuf>,
+    // `Some(None)` measures against the default base.
+    mark_local: Option<Option<String>>,
+    log_file: Option<PathBuf>,
+    previewer: Option<String>,
+    // `Some(None)` pins HEAD.
+    pin: Option<Option<String>>,
+    mode: CommitMode,
+    staged_only: bool,
+    no_merges: bool,
+    first_parent: bool,
+    hide_fixups: bool,
+    wrap: bool,
+    print: bool,
+    allow_empty: bool,
+    graph: bool,
+    topo_order: bool,
+    no_color: bool,
+    no_mouse: bool,
+    resume: bool,
+    confirm: bool,
+    // Hidden from the walk, for `<since>..HEAD`.
+    since: Option<String>,
 }
 
-// Returns the indices of commits that fit within `limit` display lines starting at `scroll`.
-fn collect_visible(
-    commits: &[String],
-    expanded: &HashSet<usize>,
-    bodies: &HashMap<usize, Vec<String>>,
-    scroll: usize,
-    limit: usize,
-) -> Vec<usize> {
-    let mut vis = Vec::new();
-    let mut lines = 0usize;
-    for idx in scroll..commits.len() {
-        let h = 1 + if expanded.contains(&idx) {
-            bodies.get(&idx).map_or(0, |b| b.len())
-        } else {
-            0
-        };
-        if lines + h > limit {
-            break;
+// Which kind of `git commit` the target gets.
+#[derive(Clone, Copy, Debug, Default)]
+enum CommitMode {
+    #[default]
+    Fixup,
+    Squash,
+    // `amend!`: replaces the target's message as well as folding in the changes.
+    Amend,
+    // `amend!` with nothing staged: only the target's message changes.
+    Reword,
+}
+
+impl CommitMode {
+    fn name(self) -> &'static str {
+        match self {
+            CommitMode::Fixup => "fixup",
+            CommitMode::Squash => "squash",
+            CommitMode::Amend => "amend",
+            CommitMode::Reword => "reword",
         }
-        vis.push(idx);
-        lines += h;
     }
-    vis
 }
 
-enum MenuEvent {
-    Move(i32),
-    Expand,
-    Collapse,
-    Confirm,
-    Quit,
+#[derive(Clone, Copy, Default)]
+enum HeaderStyle {
+    #[default]
+    Full,
+    Short,
+    None,
 }
 
-fn read_menu_event() -> MenuEvent {
-    loop {
-        match event::read().unwrap() {
-            Event::Key(key) => match key.code {
-                KeyCode::Up | KeyCode::Char('k') => return MenuEvent::Move(-1),
-                KeyCode::Down | KeyCode::Char('j') => return MenuEvent::Move(1),
-                KeyCode::Right | KeyCode::Char('l') => return MenuEvent::Expand,
-                KeyCode::Left | KeyCode::Char('h') => return MenuEvent::Collapse,
-                KeyCode::Enter => return MenuEvent::Confirm,
-                KeyCode::Char('q') | KeyCode::Esc => return MenuEvent::Quit,
-                _ => {}
-            },
-            _ => {}
+impl HeaderStyle {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "full" => Some(HeaderStyle::Full),
+            "short" => Some(HeaderStyle::Short),
+            "none" => Some(HeaderStyle::None),
+            _ => None,
         }
     }
 }
 
-fn run_menu(commits: &mut Vec<String>, repo: &Repository, revwalk: &mut Revwalk) -> Option<usize> {
-    let mut stdout = BufWriter::new(io::stdout().lock());
-    let mut selected = 0usize;
-    let mut scroll = 0usize;
-    let mut expanded: HashSet<usize> = HashSet::new();
-    let mut bodies: HashMap<usize, Vec<String>> = HashMap::new();
+// The menu's colors by what they mark.
+#[derive(Clone, Copy)]
+struct Theme {
+    // The title, and warnings such as nothing being staged.
+    header: Color,
+    // Author and date columns, indicators, hints and other secondary text.
+    dim: Color,
+    sha: Color,
+    score: Color,
+    decoration: Color,
+    graph: Color,
+    // The --mark-local gutter.
+    gutter: Color,
+    // The staged line when the selected commit changed none of the staged files, or some.
+    clean: Color,
+    overlap: Color,
+    body: Color,
+    // Search matches, which are also underlined.
+    highlight: Color,
+    // The search and jump lines.
+    search: Color,
+    notice: Color,
+    patch_header: Color,
+    patch_added: Color,
+    patch_removed: Color,
+    patch_hunk: Color,
+}
 
-    terminal::enable_raw_mode().expect("failed to enable raw mode");
-    execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide).unwrap();
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            header: Color::Yellow,
+            dim: Color::DarkGrey,
+            sha: Color::Green,
+            score: Color::Cyan,
+            decoration: Color::Yellow,
+            graph: Color::Blue,
+            gutter: Color::Magenta,
+            clean: Color::Green,
+            overlap: Color::Yellow,
+            body: Color::Grey,
+            highlight: Color::Yellow,
+            search: Color::Magenta,
+            notice: Color::Cyan,
+            patch_header: Color::White,
+            patch_added: Color::Green,
+            patch_removed: Color::Red,
+            patch_hunk: Color::Cyan,
+        }
+    }
+}
 
-    let result = loop {
-        let (_, rows) = terminal::size().unwrap();
-        let visible_count = (rows as usize).saturating_sub(2);
-        let has_more_above = scroll > 0;
-        let base_slots = visible_count.saturating_sub(has_more_above as usize);
-
-        // Two-pass: try fitting in base_slots; if we don't reach the end, reserve 1 for indicator.
-        let vis_all = collect_visible(commits, &expanded, &bodies, scroll, base_slots);
-        let (vis_commits, has_more_below) = if vis_all
-            .last()
-            .map(|&i| i + 1 < commits.len())
-            .unwrap_or(false)
-        {
-            (
-                collect_visible(commits, &expanded, &bodies, scroll, base_slots - 1),
-                true,
-            )
-        } else {
-            (vis_all, false)
-        };
+impl Theme {
+    // Everything in the terminal's default color, for --no-color.
+    fn plain() -> Self {
+        let c = Color::Reset;
+        Theme {
+            header: c,
+            dim: c,
+            sha: c,
+            score: c,
+            decoration: c,
+            graph: c,
+            gutter: c,
+            clean: c,
+            overlap: c,
+            body: c,
+            highlight: c,
+            search: c,
+            notice: c,
+            patch_header: c,
+            patch_added: c,
+            patch_removed: c,
+            patch_hunk: c,
+        }
+    }
+}
 
-        // --- Render ---
-        queue!(
-            stdout,
-            terminal::Clear(ClearType::All),
-            cursor::MoveTo(0, 0)
-        )
-        .unwrap();
-        queue!(
-            stdout,
-            style::SetForegroundColor(Color::Yellow),
-            style::Print(
-                "Select a commit  ↑/↓ move  →/← expand/collapse  Enter confirm  q quit\r\n\n"
-            ),
-            style::ResetColor,
-        )
-        .unwrap();
+#[derive(Clone, Copy, PartialEq)]
+enum StatsFormat {
+    Text,
+    Json,
+}
 
-        if has_more_above {
-            queue!(
-                stdout,
-                style::SetForegroundColor(Color::DarkGrey),
-                style::Print("  ↑ more commits above...\r\n"),
-                style::ResetColor,
-            )
-            .unwrap();
+// How the selected row stands out from the rest of the list.
+#[derive(Clone, Copy, Default)]
+enum SelectionStyle {
+    #[default]
+    Reverse,
+    Background(Color),
+    Bold,
+    Bar,
+}
+
+impl SelectionStyle {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "reverse" => Some(SelectionStyle::Reverse),
+            "bold" => Some(SelectionStyle::Bold),
+            "bar" => Some(SelectionStyle::Bar),
+            _ => {
+                let color = value.strip_prefix("bg:")?;
+                Color::try_from(color).ok().map(SelectionStyle::Background)
+            }
         }
+    }
 
-        for (vi, &abs) in vis_commits.iter().enumerate() {
-            let is_last_commit = vi + 1 == vis_commits.len();
-            let is_expanded = expanded.contains(&abs);
-            let summary_eol = if is_expanded || !is_last_commit || has_more_below {
-                "\r\n"
-            } else {
-                ""
-            };
+    fn marker(self) -> char {
+        match self {
+            SelectionStyle::Bar => '▌',
+            _ => '>',
+        }
+    }
 
-            let sha = &commits[abs][..7];
-            let summary = &commits[abs][8..];
+    fn apply(self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            SelectionStyle::Reverse => queue!(out, style::SetAttribute(Attribute::Reverse)),
+            SelectionStyle::Background(color) => queue!(out, style::SetBackgroundColor(color)),
+            SelectionStyle::Bold => queue!(out, style::SetAttribute(Attribute::Bold)),
+            SelectionStyle::Bar => Ok(()),
+        }
+    }
+}
 
-            if abs == selected {
-                queue!(
-                    stdout,
-                    style::SetAttribute(Attribute::Reverse),
-                    style::Print("> "),
-                    style::SetForegroundColor(Color::Green),
-                    style::Print(sha),
-                    style::ResetColor,
-                    style::SetAttribute(Attribute::Reverse),
-                    style::Print(format!(" {summary}{summary_eol}")),
-                    style::SetAttribute(Attribute::Reset),
-                )
-                .unwrap();
-            } else {
-                queue!(
-                    stdout,
-                    style::Print("  "),
-                    style::SetForegroundColor(Color::Green),
-                    style::Print(sha),
-                    style::ResetColor,
-                    style::Print(format!(" {summary}{summary_eol}")),
-                )
-                .unwrap();
+// Fills in options the command line left unset from the `fixupMenu.*` git config keys.
+fn apply_config(repo: &Repository, opts: &mut Options) {
+    // https://no-color.org: set and not empty.
+    if std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        opts.no_color = true;
+    }
+    let Ok(config) = repo.config() else {
+        return;
+    };
+    if let Ok(value) = config.get_string("color.ui") {
+        if matches!(value.as_str(), "never" | "false" | "off" | "no") {
+            opts.no_color = true;
+        }
+    }
+    if opts.header.is_none() {
+        if let Ok(value) = config.get_string("fixupMenu.header") {
+            opts.header = HeaderStyle::parse(&value);
+            if opts.header.is_none() {
+                eprintln!("Ignoring fixupMenu.header = `{value}`; expected full, short or none");
             }
+        }
+    }
+    if opts.message_template.is_none() {
+        opts.message_template = config.get_path("commit.template").ok();
+    }
+    if opts.pin.is_none() {
+        opts.pin = config.get_string("fixupMenu.pin").ok().map(Some);
+    }
+    if opts.previewer.is_none() {
+        opts.previewer = config.get_string("fixupMenu.previewer").ok();
+    }
+    if config.get_bool("fixupMenu.resume").unwrap_or(false) {
+        opts.resume = true;
+    }
+}
 
-            if is_expanded {
-                if let Some(body) = bodies.get(&abs) {
-                    for (j, line) in body.iter().enumerate() {
-                        let is_last_body = is_last_commit && j + 1 == body.len();
-                        let body_eol = if !is_last_body || has_more_below {
-                            "\r\n"
-                        } else {
-                            ""
-                        };
-                        queue!(
-                            stdout,
-                            style::SetForegroundColor(Color::Grey),
-                            style::Print(format!("    {line}{body_eol}")),
-                            style::ResetColor,
-                        )
-                        .unwrap();
-                    }
+// Returns the flag's value, given inline as `--flag=value` or as the next argument.
+fn flag_value(
+    name: &str,
+    inline: Option<String>,
+    args: &mut impl Iterator<Item = String>,
+) -> String {
+    inline.or_else(|| args.next()).unwrap_or_else(|| {
+        eprintln!("{name} needs a value\n\n{USAGE}");
+        std::process::exit(2);
+    })
+}
+
+fn parse_count(name: &str, value: &str) -> usize {
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("{name} expects a number, got `{value}`");
+        std::process::exit(2);
+    })
+}
+
+// The first flag given that shapes the history walk, if --stdin or --filter-fixup-target
+// replace the walk. New walk flags belong in this table.
+fn walk_only_flag(opts: &Options) -> Option<&'static str> {
+    if !opts.stdin && opts.filter_fixup_target.is_none() {
+        return None;
+    }
+    let walk_only = [
+        (opts.limit_to_branch.is_some(), "--limit-to-branch"),
+        (opts.since.is_some(), "--since"),
+        (opts.stats.is_some(), "--stats"),
+        (opts.count_only, "--count-only"),
+        (opts.target_file.is_some(), "--target-file"),
+        (opts.window.is_some(), "--window"),
+        (opts.limit.is_some(), "--limit"),
+        (opts.graph, "--graph"),
+        (opts.topo_order, "--topo-order"),
+        (opts.staged_only, "--staged-only"),
+        (opts.no_merges, "--no-merges"),
+        (opts.resume, "--resume"),
+        (opts.first_parent, "--first-parent"),
+        (opts.hide_fixups, "--hide-fixups"),
+    ];
+    walk_only
+        .iter()
+        .find(|(set, _)| *set)
+        .map(|&(_, flag)| flag)
+}
+
+// For counts where 0 would mean nothing at all, rather than no cap.
+fn parse_positive(name: &str, value: &str) -> usize {
+    let count = parse_count(name, value);
+    if count == 0 {
+        eprintln!("{name} expects a number of at least 1, got `{value}`");
+        std::process::exit(2);
+    }
+    count
+}
+
+fn parse_args() -> Options {
+    let mut opts = Options::default();
+    let mut args = std::env::args().skip(1).peekable();
+    while let Some(arg) = args.next() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg.clone(), None),
+        };
+        match name.as_str() {
+            // The base may also follow as its own argument; a <since> couldn't go with this
+            // flag anyway.
+            "--filter-fixup-target" => {
+                let value = value.or_else(|| args.next_if(|next| !next.starts_with('-')));
+                opts.filter_fixup_target = Some(value);
+            }
+            "--stdin" => opts.stdin = true,
+            "-s" | "--squash" => opts.mode = CommitMode::Squash,
+            "--staged-only" => opts.staged_only = true,
+            "--no-merges" => opts.no_merges = true,
+            "--first-parent" => opts.first_parent = true,
+            "--hide-fixups" => opts.hide_fixups = true,
+            "--wrap" => opts.wrap = true,
+            "--print" => opts.print = true,
+            "--allow-empty" => opts.allow_empty = true,
+            "--graph" => opts.graph = true,
+            "--topo-order" => opts.topo_order = true,
+            "--no-color" => opts.no_color = true,
+            "--no-mouse" => opts.no_mouse = true,
+            "--resume" => opts.resume = true,
+            "--confirm" => opts.confirm = true,
+            "--since" => opts.since = Some(flag_value(&name, value, &mut args)),
+            "--interactive-rebase" => opts.interactive_rebase = true,
+            "--rebase" => opts.autosquash_rebase = true,
+            "--color-sha-by-age" => opts.color_sha_by_age = true,
+            "--stats" => {
+                opts.stats = Some(opts.stats.unwrap_or(StatsFormat::Text));
+            }
+            "--json" => opts.stats = Some(StatsFormat::Json),
+            "--count-only" => opts.count_only = true,
+            "--target-file" => opts.target_file = Some(flag_value(&name, value, &mut args)),
+            "--yes" => opts.yes = true,
+            "--mark-local" => opts.mark_local = Some(value),
+            "--pin" => opts.pin = Some(value),
+            "--previewer" => opts.previewer = Some(flag_value(&name, value, &mut args)),
+            "--log-file" => opts.log_file = Some(flag_value(&name, value, &mut args).into()),
+            "--editor-message-template" => {
+                let path = PathBuf::from(flag_value(&name, value, &mut args));
+                if !path.is_file() {
+                    eprintln!("--editor-message-template: no such file {}", path.display());
+                    std::process::exit(2);
                 }
+                opts.message_template = Some(path);
+            }
+            "--window" => {
+                let value = flag_value(&name, value, &mut args);
+                opts.window = Some(parse_positive(&name, &value));
+            }
+            "--limit" => {
+                let value = flag_value(&name, value, &mut args);
+                opts.limit = Some(parse_positive(&name, &value));
+            }
+            "--header" => {
+                let value = flag_value(&name, value, &mut args);
+                opts.header = Some(HeaderStyle::parse(&value).unwrap_or_else(|| {
+                    eprintln!("--header expects full, short or none, got `{value}`");
+                    std::process::exit(2);
+                }));
+            }
+            "--limit-to-branch" => opts.limit_to_branch = Some(value),
+            "--branch-base" => opts.branch_base = Some(flag_value(&name, value, &mut args)),
+            "--selection-style" => {
+                let value = flag_value(&name, value, &mut args);
+                opts.selection_style = SelectionStyle::parse(&value).unwrap_or_else(|| {
+                    eprintln!(
+                        "--selection-style expects reverse, bold, bar or bg:<color>, got `{value}`"
+                    );
+                    std::process::exit(2);
+                });
+            }
+            "--cache-entries" => {
+                let value = flag_value(&name, value, &mut args);
+                opts.cache_entries = Some(parse_count(&name, &value));
+            }
+            "--max-body-lines" => {
+                let value = flag_value(&name, value, &mut args);
+                opts.max_body_lines = Some(parse_count(&name, &value));
+            }
+            "-h" | "--help" => {
+                print!("{USAGE}");
+                std::process::exit(0);
+            }
+            _ if !arg.starts_with('-') && opts.since.is_none() => opts.since = Some(arg),
+            _ => {
+                eprintln!("Unknown argument: {arg}\n\n{USAGE}");
+                std::process::exit(2);
             }
         }
+    }
+    if opts.stdin && opts.filter_fixup_target.is_some() {
+        eprintln!("--stdin can't be combined with --filter-fixup-target");
+        std::process::exit(2);
+    }
+    if let Some(flag) = walk_only_flag(&opts) {
+        eprintln!("{flag} only applies when walking history");
+        std::process::exit(2);
+    }
+    if opts.since.is_some() && opts.limit_to_branch.is_some() {
+        eprintln!("--since can't be combined with --limit-to-branch, which picks its own range");
+        std::process::exit(2);
+    }
+    // Rows re-read after leaving the window would come back without their graph.
+    if opts.graph && opts.window.is_some() {
+        eprintln!("--graph can't be combined with --window");
+        std::process::exit(2);
+    }
+    if opts.interactive_rebase && opts.autosquash_rebase {
+        eprintln!("--interactive-rebase and --rebase can't be combined");
+        std::process::exit(2);
+    }
+    if opts.print && (opts.interactive_rebase || opts.autosquash_rebase) {
+        eprintln!("--print doesn't commit, so there is nothing to rebase");
+        std::process::exit(2);
+    }
+    if opts.first_parent && opts.graph {
+        eprintln!("--graph has no branches to draw with --first-parent");
+        std::process::exit(2);
+    }
+    if opts.yes && opts.target_file.is_none() {
+        eprintln!("--yes needs --target-file");
+        std::process::exit(2);
+    }
+    if opts.branch_base.is_some() && opts.limit_to_branch.is_none() {
+        eprintln!("--branch-base needs --limit-to-branch");
+        std::process::exit(2);
+    }
+    opts
+}
 
-        if has_more_below {
-            queue!(
-                stdout,
-                style::SetForegroundColor(Color::DarkGrey),
-                style::Print("  ↓ more commits below..."),
-                style::ResetColor,
-            )
-            .unwrap();
+#[derive(Clone)]
+struct CommitRow {
+    oid: Oid,
+    summary: String,
+    // Number of staged lines owned by this commit, when ranking fixup targets.
+    score: Option<usize>,
+    // The parentless initial commit; there is nothing for autosquash to rebase it onto.
+    is_root: bool,
+    // Commit time in seconds since the epoch.
+    time: i64,
+    author: String,
+    // This commit's row of the --graph column, e.g. `| * `.
+    graph: String,
+}
+
+impl CommitRow {
+    fn new(commit: &Commit) -> Self {
+        CommitRow {
+            oid: commit.id(),
+            summary: commit.summary().unwrap_or("").to_string(),
+            score: None,
+            is_root: commit.parent_count() == 0,
+            time: commit.time().seconds(),
+            author: commit.author().name().unwrap_or("").to_string(),
+            graph: String::new(),
         }
+    }
 
-        stdout.flush().unwrap();
+    fn short_sha(&self) -> String {
+        self.oid.to_string()[..7].to_string()
+    }
 
-        // --- Events ---
-        match read_menu_event() {
-            MenuEvent::Move(delta) => {
-                let next = selected as i32 + delta;
-                if next >= 0 && next < commits.len() as i32 {
-                    selected = next as usize;
-                    if selected < scroll {
-                        scroll = selected;
-                    } else if !vis_commits.contains(&selected) {
-                        scroll += 1;
-                        if !has_more_above && scroll > 0 {
-                            scroll += 1;
-                        }
-                    }
-                    if selected + visible_count >= commits.len() {
-                        fetch_more(repo, revwalk, visible_count, commits);
-                    }
-                }
+    // The overlap score shown after the sha when ranking fixup targets.
+    fn score_label(&self) -> String {
+        self.score
+            .map_or(String::new(), |score| format!(" [{score}]"))
+    }
+
+    fn is_fixup(&self) -> bool {
+        is_fixup_summary(&self.summary)
+    }
+
+    fn root_label(&self) -> &'static str {
+        if self.is_root {
+            " (root)"
+        } else {
+            ""
+        }
+    }
+
+    // The author and age columns between the sha and the summary, padded so summaries line up.
+    fn columns(&self, now: i64) -> String {
+        let author = truncate_to(&self.author, AUTHOR_WIDTH);
+        let pad = " ".repeat(AUTHOR_WIDTH.saturating_sub(display_width(&author)));
+        format!(" {author}{pad} {:>14}", relative_time(self.time, now))
+    }
+}
+
+const AUTHOR_WIDTH: usize = 12;
+
+// How long ago `time` was, in the largest whole unit, e.g. "3 days ago".
+fn relative_time(time: i64, now: i64) -> String {
+    let secs = (now - time).max(0);
+    let (count, unit) = match secs {
+        0..=59 => return "just now".to_string(),
+        60..=3_599 => (secs / 60, "minute"),
+        3_600..=86_399 => (secs / 3_600, "hour"),
+        86_400..=604_799 => (secs / 86_400, "day"),
+        604_800..=2_629_799 => (secs / 604_800, "week"),
+        2_629_800..=31_557_599 => (secs / 2_629_800, "month"),
+        _ => (secs / 31_557_600, "year"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{count} {unit}{plural} ago")
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
+
+// The loaded commits, addressed by their position in the list. Every loaded commit keeps its
+// Oid, but once `keep_window` is used only the rows around the viewport stay in memory; the
+// rest are dropped and re-read from their Oid when they come back into view.
+struct CommitList {
+    oids: Vec<Oid>,
+    rows: VecDeque<CommitRow>,
+    // Position of `rows[0]`.
+    start: usize,
+    // Lanes for --graph, carried from one batch of the walk to the next.
+    graph: Option<Graph>,
+}
+
+// The lines of a `git log --graph` style column. Each lane holds the commit its line leads to
+// next, so a commit's lane is the one expecting it.
+#[derive(Default)]
+struct Graph {
+    lanes: Vec<Option<Oid>>,
+}
+
+impl Graph {
+    // Draws the row for the next commit in the walk and moves its lane on to its parents.
+    // Lanes also expecting it join it here, and parents past the first open new lanes. A lane
+    // that joins isn't reused on the same row, where its `/` would be drawn over.
+    fn row(&mut self, commit: &Commit) -> String {
+        let oid = commit.id();
+        let col = match self.lanes.iter().position(|&lane| lane == Some(oid)) {
+            Some(col) => col,
+            None => self.free_lane(&[]),
+        };
+        let mut cells = vec![' '; self.lanes.len()];
+        let mut joined = Vec::new();
+        for (i, lane) in self.lanes.iter_mut().enumerate() {
+            if i == col {
+                cells[i] = '*';
+            } else if *lane == Some(oid) {
+                *lane = None;
+                joined.push(i);
+                cells[i] = if i > col { '/' } else { '\\' };
+            } else if lane.is_some() {
+                cells[i] = '|';
             }
-            MenuEvent::Expand => {
-                if !bodies.contains_key(&selected) {
-                    let sha = &commits[selected][..7];
-                    if let Ok(obj) = repo.revparse_single(sha) {
-                        if let Ok(commit) = obj.peel_to_commit() {
-                            let msg = commit.message().unwrap_or("").to_string();
-                            let body: Vec<String> = msg
-                                .lines()
-                                .skip(1)
-                                .skip_while(|l| l.trim().is_empty())
-                                .map(|l| l.to_string())
-                                .collect();
-                            let body = if body.is_empty() {
-                                vec!["(no description)".to_string()]
-                            } else {
-                                body
-                            };
-                            bodies.insert(selected, body);
-                        }
-                    }
-                }
-                expanded.insert(selected);
+        }
+        let mut parents = commit.parent_ids();
+        self.lanes[col] = parents.next();
+        for parent in parents {
+            if self.lanes.contains(&Some(parent)) {
+                continue;
             }
-            MenuEvent::Collapse => {
-                expanded.remove(&selected);
+            let lane = self.free_lane(&joined);
+            self.lanes[lane] = Some(parent);
+            if lane >= cells.len() {
+                cells.resize(lane + 1, ' ');
             }
-            MenuEvent::Confirm => break Some(selected),
-            MenuEvent::Quit => break None,
+            cells[lane] = if lane > col { '\\' } else { '/' };
         }
-    };
+        while self.lanes.last() == Some(&None) {
+            self.lanes.pop();
+        }
+        let mut row: String = cells.iter().flat_map(|&cell| [cell, ' ']).collect();
+        row.truncate(row.trim_end().len());
+        row.push(' ');
+        row
+    }
 
-    execute!(stdout, terminal::LeaveAlternateScreen, cursor::Show).unwrap();
-    terminal::disable_raw_mode().expect("failed to disable raw mode");
+    fn free_lane(&mut self, skip: &[usize]) -> usize {
+        let free = (0..self.lanes.len()).find(|i| self.lanes[*i].is_none() && !skip.contains(i));
+        match free {
+            Some(lane) => lane,
+            None => {
+                self.lanes.push(None);
+                self.lanes.len() - 1
+            }
+        }
+    }
+}
 
-    result
+// The graph column under a commit's row, for its body and wrapped lines: the lanes carry on
+// and the ones that just joined stop.
+fn graph_below(graph: &str) -> String {
+    graph
+        .chars()
+        .map(|c| match c {
+            '*' | '\\' => '|',
+            '/' => ' ',
+            c => c,
+        })
+        .collect()
 }
 
-fn create_fixup_commit(sha: &str) {
-    let status = Command::new("git")
-        .args(["commit", "--fixup", sha])
-        .status()
-        .expect("failed to run git commit --fixup");
+impl CommitList {
+    fn new(rows: Vec<CommitRow>) -> Self {
+        CommitList {
+            oids: rows.iter().map(|row| row.oid).collect(),
+            rows: rows.into(),
+            start: 0,
+            graph: None,
+        }
+    }
 
-    if !status.success() {
-        eprintln!("git commit --fixup failed");
-        std::process::exit(1);
+    fn len(&self) -> usize {
+        self.oids.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.oids.is_empty()
+    }
+
+    fn push(&mut self, row: CommitRow) {
+        // Only grow the resident rows while they reach the end of the list.
+        if self.start + self.rows.len() == self.oids.len() {
+            self.rows.push_back(row.clone());
+        }
+        self.oids.push(row.oid);
+    }
+
+    fn oid(&self, idx: usize) -> Oid {
+        self.oids[idx]
+    }
+
+    fn position(&self, oid: Oid) -> Option<usize> {
+        self.oids.iter().position(|&o| o == oid)
+    }
+
+    // The rows currently held in memory.
+    fn resident(&self) -> impl Iterator<Item = &CommitRow> {
+        self.rows.iter()
+    }
+
+    // Returns the row at `idx`, re-reading it if it has been dropped.
+    fn get(&self, repo: &Repository, idx: usize) -> Result<CommitRow> {
+        match idx.checked_sub(self.start).and_then(|i| self.rows.get(i)) {
+            Some(row) => Ok(row.clone()),
+            None => Self::load(repo, self.oids[idx]),
+        }
+    }
+
+    fn load(repo: &Repository, oid: Oid) -> Result<CommitRow> {
+        let commit = repo
+            .find_commit(oid)
+            .map_err(|e| format!("commit {oid} vanished from the repository: {}", e.message()))?;
+        Ok(CommitRow::new(&commit))
+    }
+
+    // Keeps just the rows in `from..to` in memory, re-reading any that were dropped.
+    fn keep_window(&mut self, repo: &Repository, from: usize, to: usize) -> Result<()> {
+        let to = to.min(self.len());
+        let from = from.min(to);
+        let end = self.start + self.rows.len();
+        if from >= end || to <= self.start {
+            self.rows = (from..to)
+                .map(|idx| Self::load(repo, self.oids[idx]))
+                .collect::<Result<_>>()?;
+            self.start = from;
+            return Ok(());
+        }
+        while self.start > from {
+            let row = Self::load(repo, self.oids[self.start - 1])?;
+            self.start -= 1;
+            self.rows.push_front(row);
+        }
+        for idx in end..to {
+            self.rows.push_back(Self::load(repo, self.oids[idx])?);
+        }
+        while self.start < from {
+            self.rows.pop_front();
+            self.start += 1;
+        }
+        self.rows.truncate(to - self.start);
+        Ok(())
     }
 }
 
-fn main() {
-    let repo = Repository::discover(".");
-    if repo.is_err() {
-        eprintln!("Not a valid git repo.");
-        return;
+// Which walked commits make it into the list.
+#[derive(Clone, Copy, Default)]
+struct WalkFilter<'a> {
+    // Only commits touching these, for --staged-only.
+    paths: Option<&'a [PathBuf]>,
+    no_merges: bool,
+    hide_fixups: bool,
+    // --limit: the list never grows past this.
+    limit: Option<usize>,
+}
+
+impl WalkFilter<'_> {
+    fn at_limit(&self, commits: &CommitList) -> bool {
+        self.limit.is_some_and(|limit| commits.len() >= limit)
     }
-    let repo = repo.unwrap();
-    let mut revwalk = repo.revwalk().expect("failed to create revwalk");
-    revwalk
-        .set_sorting(Sort::TIME | Sort::TOPOLOGICAL)
-        .expect("failed to set sorting");
-    revwalk.push_head().expect("failed to push HEAD");
+}
 
-    let (_, rows) = terminal::size().expect("failed to get terminal size");
-    let initial = (rows as usize) * 2;
-    let mut commits = Vec::new();
-    fetch_more(&repo, &mut revwalk, initial, &mut commits);
+// Commits made by `git commit --fixup` and friends, waiting for an autosquash. A
+// `fixup! fixup! ...` still starts with the prefix, so it needs no special case.
+fn is_fixup_summary(summary: &str) -> bool {
+    ["fixup!", "squash!", "amend!"]
+        .iter()
+        .any(|prefix| summary.starts_with(prefix))
+}
 
-    if commits.is_empty() {
-        eprintln!("No commits found.");
-        return;
+// The toast for moving past the end of a list cut short by --limit.
+fn limit_note(loaded: usize) -> String {
+    format!("Stopped at --limit: {loaded} commits loaded")
+}
+
+// Adds up to `n` more commits that pass `keep`. Returns true once the walk has run out or
+// the list has reached its limit.
+fn fetch_more(
+    repo: &Repository,
+    revwalk: &mut Revwalk,
+    n: usize,
+    commits: &mut CommitList,
+    keep: WalkFilter,
+) -> Result<bool> {
+    let mut added = 0usize;
+    while added < n {
+        if keep.at_limit(commits) {
+            return Ok(true);
+        }
+        let Some(oid) = revwalk.next() else {
+            return Ok(true);
+        };
+        let commit = repo.find_commit(oid?)?;
+        // Every walked commit moves the lanes on, listed or not, so they still line up.
+        let graph = commits.graph.as_mut().map(|graph| graph.row(&commit));
+        if keep.no_merges && commit.parent_count() > 1 {
+            continue;
+        }
+        if keep.hide_fixups && is_fixup_summary(commit.summary().unwrap_or("")) {
+            continue;
+        }
+        if let Some(paths) = keep.paths {
+            if count_touched(repo, &commit, paths)? == 0 {
+                continue;
+            }
+        }
+        let mut row = CommitRow::new(&commit);
+        row.graph = graph.unwrap_or_default();
+        commits.push(row);
+        added += 1;
+    }
+    Ok(false)
+}
+
+// How many of `paths` the commit changed relative to its first parent.
+fn count_touched(repo: &Repository, commit: &Commit, paths: &[PathBuf]) -> Result<usize> {
+    let parent_tree = match commit.parent(0) {
+        Ok(parent) => Some(parent.tree()?),
+        Err(_) => None,
+    };
+    let mut diff_opts = DiffOptions::new();
+    diff_opts.disable_pathspec_match(true);
+    for path in paths {
+        diff_opts.pathspec(path);
+    }
+    let diff = repo.diff_tree_to_tree(
+        parent_tree.as_ref(),
+        Some(&commit.tree()?),
+        Some(&mut diff_opts),
+    )?;
+    Ok(diff.deltas().len())
+}
+
+// Paths with staged changes, on both sides of a rename.
+fn staged_paths(repo: &Repository) -> Result<Vec<PathBuf>> {
+    let head_tree = repo.head().and_then(|head| head.peel_to_tree()).ok();
+    let index = repo.index()?;
+    let diff = repo.diff_tree_to_index(head_tree.as_ref(), Some(&index), None)?;
+    let mut paths: Vec<PathBuf> = diff
+        .deltas()
+        .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+        .flatten()
+        .map(Path::to_path_buf)
+        .collect();
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+// The commits unique to a branch: its tip, and the merge-base with its base to hide.
+struct BranchRange {
+    tip: Oid,
+    merge_base: Oid,
+    label: String,
+}
+
+// What a branch is measured against when no base is given: its upstream, else main or master.
+fn default_base(repo: &Repository, branch: Option<&Branch>, name: &str) -> Option<String> {
+    if let Some(upstream) = branch.and_then(|branch| branch.upstream().ok()) {
+        return Some(upstream.name().ok().flatten().unwrap_or("").to_string());
+    }
+    ["main", "master"]
+        .into_iter()
+        .find(|candidate| *candidate != name && repo.revparse_single(candidate).is_ok())
+        .map(str::to_string)
+}
+
+// The branch and tag names pointing at each commit, local branches first, as `git log
+// --decorate` shows them. Symbolic refs such as origin/HEAD and the stash are left out.
+fn ref_decorations(repo: &Repository) -> HashMap<Oid, Vec<String>> {
+    let mut names: HashMap<Oid, Vec<(u8, String)>> = HashMap::new();
+    let Ok(references) = repo.references() else {
+        return HashMap::new();
+    };
+    for reference in references.flatten() {
+        if reference.symbolic_target().is_some() || reference.name() == Some("refs/stash") {
+            continue;
+        }
+        let order = if reference.is_branch() {
+            1
+        } else if reference.is_remote() {
+            2
+        } else {
+            3
+        };
+        let (Ok(commit), Some(name)) = (reference.peel_to_commit(), reference.shorthand()) else {
+            continue;
+        };
+        names
+            .entry(commit.id())
+            .or_default()
+            .push((order, name.to_string()));
+    }
+    // HEAD leads its commit's list, pointing at the branch it's on.
+    if let Ok(head) = repo.head() {
+        if let Ok(commit) = head.peel_to_commit() {
+            let names = names.entry(commit.id()).or_default();
+            let branch = head.is_branch().then(|| head.shorthand()).flatten();
+            match names
+                .iter_mut()
+                .find(|(order, name)| *order == 1 && Some(name.as_str()) == branch)
+            {
+                Some(entry) => *entry = (0, format!("HEAD -> {}", entry.1)),
+                None => names.push((0, "HEAD".to_string())),
+            }
+        }
     }
+    names
+        .into_iter()
+        .map(|(oid, mut names)| {
+            names.sort();
+            (oid, names.into_iter().map(|(_, name)| name).collect())
+        })
+        .collect()
+}
+
+// Commits reachable from HEAD but not from the base, i.e. the ones a push would publish.
+fn local_commits(repo: &Repository, base: Option<&str>) -> Result<HashSet<Oid>, String> {
+    let head = repo.head().map_err(|e| e.message().to_string())?;
+    let name = head.shorthand().unwrap_or("HEAD").to_string();
+    let branch = head
+        .is_branch()
+        .then(|| repo.find_branch(&name, BranchType::Local).ok())
+        .flatten();
+    let base = match base {
+        Some(base) => base.to_string(),
+        None => default_base(repo, branch.as_ref(), &name)
+            .ok_or_else(|| format!("no base found for `{name}`; pass --mark-local=<rev>"))?,
+    };
+    let boundary = repo
+        .revparse_single(&base)
+        .and_then(|obj| obj.peel_to_commit())
+        .map_err(|_| format!("`{base}` doesn't name a commit"))?
+        .id();
+
+    let mut walk = repo.revwalk().map_err(|e| e.message().to_string())?;
+    walk.push_head().map_err(|e| e.message().to_string())?;
+    walk.hide(boundary).map_err(|e| e.message().to_string())?;
+    Ok(walk.filter_map(Result::ok).collect())
+}
+
+fn branch_range(
+    repo: &Repository,
+    name: Option<&str>,
+    base: Option<&str>,
+) -> Result<BranchRange, String> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => {
+            let head = repo.head().map_err(|e| e.message().to_string())?;
+            if !head.is_branch() {
+                return Err("HEAD is detached; pass --limit-to-branch=<name>".to_string());
+            }
+            head.shorthand().unwrap_or("HEAD").to_string()
+        }
+    };
+    let branch = repo
+        .find_branch(&name, BranchType::Local)
+        .map_err(|_| format!("no local branch named `{name}`"))?;
+    let tip = branch
+        .get()
+        .peel_to_commit()
+        .map_err(|e| e.message().to_string())?
+        .id();
+
+    let base = match base {
+        Some(base) => base.to_string(),
+        None => default_base(repo, Some(&branch), &name)
+            .ok_or_else(|| format!("no base found for `{name}`; pass --branch-base <rev>"))?,
+    };
+    let base_oid = repo
+        .revparse_single(&base)
+        .and_then(|obj| obj.peel_to_commit())
+        .map_err(|_| format!("`{base}` doesn't name a commit"))?
+        .id();
+    let merge_base = repo
+        .merge_base(tip, base_oid)
+        .map_err(|_| format!("`{name}` and `{base}` share no history"))?;
+
+    Ok(BranchRange {
+        tip,
+        merge_base,
+        label: format!("{base}..{name}"),
+    })
+}
+
+// Parses `<sha> <summary>` lines from stdin, dropping any whose sha doesn't resolve to a commit.
+fn read_stdin_commits(repo: &Repository) -> Result<Vec<CommitRow>> {
+    let mut commits = Vec::new();
+    for line in io::stdin().lines() {
+        let line = line.map_err(|e| format!("failed to read stdin: {e}"))?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (sha, summary) = line.split_once(' ').unwrap_or((line, ""));
+        let commit = match repo
+            .revparse_single(sha)
+            .and_then(|obj| obj.peel_to_commit())
+        {
+            Ok(commit) => commit,
+            Err(_) => {
+                eprintln!("Skipping {sha}: not a commit in this repository");
+                continue;
+            }
+        };
+        let mut row = CommitRow::new(&commit);
+        if !summary.trim().is_empty() {
+            row.summary = summary.trim().to_string();
+        }
+        commits.push(row);
+    }
+    Ok(commits)
+}
+
+// Staged hunks as (path, first line, line count) in HEAD's version of the file.
+fn staged_line_ranges(repo: &Repository) -> Result<Vec<(PathBuf, usize, usize)>, git2::Error> {
+    let head_tree = repo.head()?.peel_to_tree()?;
+    let index = repo.index()?;
+    let mut diff_opts = DiffOptions::new();
+    diff_opts.context_lines(0);
+    let diff = repo.diff_tree_to_index(Some(&head_tree), Some(&index), Some(&mut diff_opts))?;
+
+    let mut ranges = Vec::new();
+    diff.foreach(
+        &mut |_, _| true,
+        None,
+        Some(&mut |delta, hunk| {
+            if let Some(path) = delta.old_file().path() {
+                let start = hunk.old_start() as usize;
+                let lines = hunk.old_lines() as usize;
+                // Pure insertions have no old lines: credit the lines on either side instead.
+                let range = if lines == 0 {
+                    (start.max(1), if start == 0 { 1 } else { 2 })
+                } else {
+                    (start, lines)
+                };
+                ranges.push((path.to_path_buf(), range.0, range.1));
+            }
+            true
+        }),
+        None,
+    )?;
+    Ok(ranges)
+}
+
+// Blames the staged hunks against HEAD and returns the owning commits, most overlap first.
+fn rank_fixup_targets(
+    repo: &Repository,
+    base: Option<&str>,
+) -> Result<Vec<CommitRow>, git2::Error> {
+    let base = base
+        .map(|rev| {
+            repo.revparse_single(rev)
+                .and_then(|obj| obj.peel_to_commit())
+        })
+        .transpose()?;
+    let ranges = staged_line_ranges(repo)?;
+
+    let mut scores: HashMap<Oid, usize> = HashMap::new();
+    let mut blames = HashMap::new();
+    for (path, start, lines) in &ranges {
+        if !blames.contains_key(path) {
+            let mut blame_opts = BlameOptions::new();
+            if let Some(base) = &base {
+                blame_opts.oldest_commit(base.id());
+            }
+            // Deleted or renamed-away files may no longer blame; skip them.
+            let Ok(blame) = repo.blame_file(Path::new(path), Some(&mut blame_opts)) else {
+                continue;
+            };
+            blames.insert(path.clone(), blame);
+        }
+        let blame = &blames[path];
+        for line in *start..start + lines {
+            if let Some(hunk) = blame.get_line(line) {
+                if !hunk.is_boundary() || base.is_none() {
+                    *scores.entry(hunk.final_commit_id()).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    let mut ranked = Vec::new();
+    for (oid, score) in scores {
+        let mut row = CommitRow::new(&repo.find_commit(oid)?);
+        row.score = Some(score);
+        ranked.push(row);
+    }
+    ranked.sort_by(|a, b| b.score.cmp(&a.score).then(b.time.cmp(&a.time)));
+    Ok(ranked)
+}
+
+// Splits a body into the lines shown under `cap`, starting `skip` lines in, and the number
+// left hidden above and below them.
+fn capped_body(body: &[String], cap: usize, skip: usize) -> (&[String], usize) {
+    if body.len() <= cap {
+        (body, 0)
+    } else {
+        let skip = skip.min(body.len() - cap);
+        (&body[skip..skip + cap], body.len() - cap)
+    }
+}
+
+fn commit_message(repo: &Repository, oid: Oid) -> Option<String> {
+    let commit = repo.find_commit(oid).ok()?;
+    Some(commit.message().unwrap_or("").to_string())
+}
+
+// The message minus its subject line, for showing under an expanded commit.
+fn message_body(msg: &str) -> Vec<String> {
+    let body: Vec<String> = msg
+        .lines()
+        .skip(1)
+        .skip_while(|l| l.trim().is_empty())
+        .map(|l| l.to_string())
+        .collect();
+    if body.is_empty() {
+        vec!["(no description)".to_string()]
+    } else {
+        body
+    }
+}
+
+// Lines a worker has finished loading for a commit.
+type LoadedLines = Receiver<(Oid, Vec<String>)>;
+
+// Loads commit bodies on a worker thread with its own handle on the repository, since
+// `Repository` can't be shared across threads. Dropping the sender stops the worker.
+fn spawn_body_loader(repo: &Repository) -> (Sender<Oid>, LoadedLines) {
+    let (request_tx, request_rx) = mpsc::channel::<Oid>();
+    let (body_tx, body_rx) = mpsc::channel();
+    let path = repo.path().to_path_buf();
+    thread::spawn(move || {
+        let Ok(repo) = Repository::open(path) else {
+            return;
+        };
+        for oid in request_rx {
+            let body = match commit_message(&repo, oid) {
+                Some(msg) => {
+                    let stat = diff_stat(&repo, oid)
+                        .unwrap_or_else(|e| format!("(no diffstat: {})", e.message()));
+                    let mut body = vec![stat];
+                    body.extend(message_body(&msg));
+                    body
+                }
+                None => vec!["(failed to load the commit message)".to_string()],
+            };
+            if body_tx.send((oid, body)).is_err() {
+                break;
+            }
+        }
+    });
+    (request_tx, body_rx)
+}
+
+// Renders previews on a worker thread too, since a previewer like delta can take a while on a
+// big commit. Requests carry the width to render for.
+fn spawn_preview_loader(
+    repo: &Repository,
+    previewer: Option<String>,
+    color: bool,
+) -> (Sender<(Oid, u16)>, LoadedLines) {
+    let (request_tx, request_rx) = mpsc::channel::<(Oid, u16)>();
+    let (preview_tx, preview_rx) = mpsc::channel();
+    let path = repo.path().to_path_buf();
+    thread::spawn(move || {
+        let Ok(repo) = Repository::open(path) else {
+            return;
+        };
+        for (oid, cols) in request_rx {
+            let lines = preview_lines(&repo, oid, previewer.as_deref(), cols, color);
+            if preview_tx.send((oid, lines)).is_err() {
+                break;
+            }
+        }
+    });
+    (request_tx, preview_rx)
+}
+
+// Loaded text for commits, holding at most `capacity` of them (0 for any number). Past that, the
+// least recently read entry is dropped, to be loaded again if it's needed.
+struct LruCache<V> {
+    entries: HashMap<Oid, (V, Cell<u64>)>,
+    capacity: usize,
+    // Bumped on every read, so older reads have lower ticks.
+    clock: Cell<u64>,
+}
+
+impl<V> LruCache<V> {
+    fn new(capacity: usize) -> Self {
+        LruCache {
+            entries: HashMap::new(),
+            capacity,
+            clock: Cell::new(0),
+        }
+    }
+
+    fn get(&self, oid: &Oid) -> Option<&V> {
+        let (value, used) = self.entries.get(oid)?;
+        self.clock.set(self.clock.get() + 1);
+        used.set(self.clock.get());
+        Some(value)
+    }
+
+    fn contains_key(&self, oid: &Oid) -> bool {
+        self.entries.contains_key(oid)
+    }
+
+    fn insert(&mut self, oid: Oid, value: V) {
+        self.clock.set(self.clock.get() + 1);
+        self.entries
+            .insert(oid, (value, Cell::new(self.clock.get())));
+        if self.capacity > 0 && self.entries.len() > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| used.get())
+                .map(|(&oid, _)| oid);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+// Moves finished loads into `bodies`. Returns true if any arrived.
+fn receive_bodies(
+    loaded: &LoadedLines,
+    bodies: &mut LruCache<Vec<String>>,
+    loading: &mut HashSet<Oid>,
+) -> bool {
+    let mut received = false;
+    for (oid, body) in loaded.try_iter() {
+        loading.remove(&oid);
+        bodies.insert(oid, body);
+        received = true;
+    }
+    received
+}
+
+// The selected commit's diff for the preview pane: `git show` through the previewer when one
+// is set, else git2's plain patch against the first parent.
+fn preview_lines(
+    repo: &Repository,
+    oid: Oid,
+    previewer: Option<&str>,
+    cols: u16,
+    color: bool,
+) -> Vec<String> {
+    let result = match previewer {
+        Some(cmd) => run_previewer(cmd, oid, cols, color),
+        None => plain_patch(repo, oid).map_err(|e| e.message().to_string()),
+    };
+    result.unwrap_or_else(|e| vec![format!("(no preview: {e})")])
+}
+
+fn run_previewer(cmd: &str, oid: Oid, cols: u16, color: bool) -> Result<Vec<String>, String> {
+    let color = if color {
+        "--color=always"
+    } else {
+        "--color=never"
+    };
+    let mut show = Command::new("git")
+        .args(["show", color, &oid.to_string()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("failed to run git show: {e}"))?;
+    let patch = show.stdout.take().expect("git show stdout is piped");
+    // Tools like delta size their output to COLUMNS when not writing to a terminal.
+    let output = Command::new("sh")
+        .args(["-c", cmd])
+        .env("COLUMNS", cols.to_string())
+        .stdin(patch)
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| format!("failed to run `{cmd}`: {e}"))?;
+    let _ = show.wait();
+    if !output.status.success() {
+        return Err(format!("`{cmd}` failed with {}", output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+// What the commit changed relative to its first parent, or to the empty tree for a root.
+fn first_parent_diff<'r>(repo: &'r Repository, oid: Oid) -> Result<Diff<'r>, git2::Error> {
+    let commit = repo.find_commit(oid)?;
+    let parent_tree = match commit.parent(0) {
+        Ok(parent) => Some(parent.tree()?),
+        Err(_) => None,
+    };
+    repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)
+}
+
+// The line heading an expanded body, e.g. "3 files changed, +12/-4".
+fn diff_stat(repo: &Repository, oid: Oid) -> Result<String, git2::Error> {
+    let stats = first_parent_diff(repo, oid)?.stats()?;
+    let files = stats.files_changed();
+    let plural = if files == 1 { "" } else { "s" };
+    Ok(format!(
+        "{files} file{plural} changed, +{}/-{}",
+        stats.insertions(),
+        stats.deletions()
+    ))
+}
+
+fn plain_patch(repo: &Repository, oid: Oid) -> Result<Vec<String>, git2::Error> {
+    let diff = first_parent_diff(repo, oid)?;
+    let mut lines = Vec::new();
+    diff.print(DiffFormat::Patch, |_, _, line| {
+        let origin = match line.origin() {
+            origin @ ('+' | '-' | ' ') => origin.to_string(),
+            _ => String::new(),
+        };
+        let content = String::from_utf8_lossy(line.content());
+        for text in content.lines() {
+            lines.push(format!("{origin}{text}"));
+        }
+        true
+    })?;
+    Ok(lines)
+}
+
+// Byte ranges in `text` where `query` occurs, ignoring case the same way the search does.
+fn match_ranges(text: &str, query: &str) -> Vec<Range<usize>> {
+    let needle: Vec<char> = query.chars().flat_map(char::to_lowercase).collect();
+    if needle.is_empty() {
+        return Vec::new();
+    }
+    // Where a match starting at `start` ends, if one does.
+    let match_at = |start: usize| {
+        let mut matched = 0;
+        for (i, c) in text[start..].char_indices() {
+            for lower in c.to_lowercase() {
+                if matched == needle.len() || lower != needle[matched] {
+                    return None;
+                }
+                matched += 1;
+            }
+            if matched == needle.len() {
+                return Some(start + i + c.len_utf8());
+            }
+        }
+        None
+    };
+    let mut ranges = Vec::new();
+    let mut from = 0;
+    for (start, _) in text.char_indices() {
+        if start < from {
+            continue;
+        }
+        if let Some(end) = match_at(start) {
+            ranges.push(start..end);
+            from = end;
+        }
+    }
+    ranges
+}
+
+// Prints `text` with `ranges` picked out in `color` and underlined. Only the foreground and
+// underline are touched, so a selection style already applied carries on around them.
+fn queue_highlighted(
+    out: &mut impl Write,
+    text: &str,
+    ranges: &[Range<usize>],
+    color: Color,
+) -> io::Result<()> {
+    let mut at = 0;
+    for range in ranges {
+        queue!(
+            out,
+            style::Print(&text[at..range.start]),
+            style::SetForegroundColor(color),
+            style::SetAttribute(Attribute::Underlined),
+            style::Print(&text[range.clone()]),
+            style::SetAttribute(Attribute::NoUnderline),
+            style::SetForegroundColor(Color::Reset),
+        )?;
+        at = range.end;
+    }
+    queue!(out, style::Print(&text[at..]))
+}
+
+fn patch_line_color(line: &str, theme: &Theme) -> Color {
+    if line.starts_with("+++") || line.starts_with("---") || line.starts_with("diff ") {
+        theme.patch_header
+    } else if line.starts_with('+') {
+        theme.patch_added
+    } else if line.starts_with('-') {
+        theme.patch_removed
+    } else if line.starts_with("@@") {
+        theme.patch_hunk
+    } else {
+        Color::Reset
+    }
+}
+
+// Cuts a line to `width` visible columns, passing escape sequences through untouched.
+fn truncate_ansi(line: &str, width: usize) -> String {
+    let mut out = String::new();
+    let mut visible = 0;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            out.push(c);
+            if chars.peek() == Some(&'[') {
+                // CSI sequences run up to and including their final letter.
+                for c in chars.by_ref() {
+                    out.push(c);
+                    if c.is_ascii_alphabetic() || c == '~' {
+                        break;
+                    }
+                }
+            }
+        } else if visible + char_width(c) <= width {
+            out.push(if c == '\t' { ' ' } else { c });
+            visible += char_width(c);
+        } else {
+            // Anything after the cut is hidden, but a later reset still has to get through.
+            visible = width;
+        }
+    }
+    out
+}
+
+// How many terminal columns `c` takes: two for East Asian wide characters and most emoji,
+// none for combining marks and zero-width characters, one for everything else.
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036F | 0x200B..=0x200F | 0x20D0..=0x20FF | 0xFE00..=0xFE0F | 0xFE20..=0xFE2F => {
+            0
+        }
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0x33FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F680..=0x1F6FF
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+fn display_width(text: &str) -> usize {
+    text.chars().map(char_width).sum()
+}
+
+// Cuts plain text to `width` columns, ending in … when anything was cut.
+fn truncate_to(text: &str, width: usize) -> String {
+    if display_width(text) <= width {
+        return text.to_string();
+    }
+    let room = width.saturating_sub(1);
+    let mut used = 0;
+    let cut: String = text
+        .chars()
+        .take_while(|&c| {
+            used += char_width(c);
+            used <= room
+        })
+        .collect();
+    format!("{cut}…")
+}
+
+// Breaks plain text into lines of at most `width` columns, at spaces where it can.
+fn wrap_to(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut line_len = 0;
+    for word in text.split(' ') {
+        let mut word = word.to_string();
+        if line_len > 0 && line_len + 1 + display_width(&word) > width {
+            lines.push(std::mem::take(&mut line));
+            line_len = 0;
+        }
+        // Words longer than a whole line are split where the line ends.
+        while display_width(&word) > width {
+            let mut used = 0;
+            let split = word
+                .char_indices()
+                .find(|&(_, c)| {
+                    used += char_width(c);
+                    used > width
+                })
+                .map_or(word.len(), |(i, _)| i);
+            // A wide character on a one-column line still has to go somewhere.
+            let split = if split == 0 {
+                word.chars().next().map_or(0, char::len_utf8)
+            } else {
+                split
+            };
+            let rest = word.split_off(split);
+            lines.push(word);
+            word = rest;
+        }
+        if line_len > 0 {
+            line.push(' ');
+            line_len += 1;
+        }
+        line_len += display_width(&word);
+        line.push_str(&word);
+    }
+    lines.push(line);
+    lines
+}
+
+// Shades a sha from bright green for the newest loaded commit down to dim for the oldest.
+fn age_color(time: i64, oldest: i64, newest: i64) -> Color {
+    let span = (newest - oldest).max(1) as f64;
+    let age = ((newest - time) as f64 / span).clamp(0.0, 1.0);
+    let fade = |bright: f64, dim: f64| (bright + (dim - bright) * age).round() as u8;
+    Color::Rgb {
+        r: fade(120.0, 70.0),
+        g: fade(255.0, 95.0),
+        b: fade(120.0, 70.0),
+    }
+}
+
+// Returns the indices of commits that fit within `limit` display lines starting at `scroll`.
+// `height` gives the number of display lines the commit at an index takes up. The first
+// commit is kept even when it's taller than `limit`, to be cut off at the bottom of the screen
+// rather than leave it empty.
+fn collect_visible(
+    len: usize,
+    scroll: usize,
+    limit: usize,
+    height: impl Fn(usize) -> usize,
+) -> Vec<usize> {
+    let mut vis = Vec::new();
+    if limit == 0 {
+        return vis;
+    }
+    let mut lines = 0usize;
+    for idx in scroll..len {
+        let h = height(idx);
+        if lines + h > limit && !vis.is_empty() {
+            break;
+        }
+        vis.push(idx);
+        lines += h;
+    }
+    vis
+}
+
+// Where the view starts for a jump to `selected`: every row takes at least a line, so from
+// more than a screen above it could never make the screen.
+fn reveal_from(scroll: usize, selected: usize, visible_count: usize) -> usize {
+    if selected < scroll {
+        selected
+    } else {
+        scroll.max(selected.saturating_sub(visible_count))
+    }
+}
+
+// With --window, keeps the rows around the view resident, from a third of the window above
+// it to far enough below that scrolling on to reveal the selection stays inside.
+fn keep_view_window(
+    commits: &mut CommitList,
+    repo: &Repository,
+    filter: &Option<Filter>,
+    scroll: usize,
+    visible_count: usize,
+    window: usize,
+) -> Result<()> {
+    let len = view_len(filter, commits);
+    // Leave room either side of the viewport so a page of movement stays resident.
+    let window = window.max(visible_count * 3);
+    let from = view_at(filter, scroll).saturating_sub(window / 3);
+    // Matches can be spread out, so stretch the window over two whole screens of them.
+    let last = view_at(filter, (scroll + 2 * visible_count).min(len - 1));
+    commits.keep_window(repo, from, (from + window).max(last + 1))
+}
+
+// Picks the rows for a screen of `visible_count` lines starting at `scroll`, and whether the
+// "above" and "below" indicators take a line each.
+fn layout_rows(
+    len: usize,
+    scroll: usize,
+    visible_count: usize,
+    height: impl Fn(usize) -> usize,
+) -> (Vec<usize>, bool, bool) {
+    // On a screen with room for only one row, the selection takes it over the indicators.
+    let has_more_above = scroll > 0 && visible_count > 1;
+    let base_slots = visible_count.saturating_sub(has_more_above as usize);
+
+    // Two-pass: try fitting in base_slots; if we don't reach the end, reserve 1 for indicator.
+    let vis_all = collect_visible(len, scroll, base_slots, &height);
+    if base_slots > 1 && vis_all.last().is_some_and(|&i| i + 1 < len) {
+        let vis = collect_visible(len, scroll, base_slots - 1, &height);
+        (vis, has_more_above, true)
+    } else {
+        (vis_all, has_more_above, false)
+    }
+}
+
+#[derive(Debug)]
+enum MenuEvent {
+    Move(i32),
+    // Moves by half screens: PageUp/PageDown are two, Ctrl-u/Ctrl-d one.
+    Page(i32),
+    Top,
+    Bottom,
+    Expand,
+    ExpandFull,
+    // Expand the selected commit and collapse every other one.
+    ExpandOnly,
+    TogglePreview,
+    ToggleFullSha,
+    // J/K: scrolls the preview, or with it closed the selected full body.
+    Scroll(i32),
+    Collapse,
+    CopySha,
+    CopyMessage,
+    Confirm,
+    // `a` and `r` confirm as amend or reword instead of the usual kind.
+    ConfirmAs(CommitMode),
+    FixupHead,
+    ToggleMark,
+    // `:` starts typing a sha to jump to, which arrives as search input.
+    JumpToSha,
+    // `/` starts editing the search; the rest arrive while it's being typed.
+    Search,
+    SearchInput(char),
+    SearchBackspace,
+    SearchDone,
+    SearchCancel,
+    // The terminal changed size.
+    Resize,
+    // A left click on this screen row.
+    Click(u16),
+    Quit,
+}
+
+// Waits for the next menu key. With a timeout, returns None if none arrives in time. While
+// `searching`, typed characters go to the query instead of acting as keys.
+// Digits typed before a move are gathered in `count`, so `5j` moves five rows. Any other key
+// drops the count, and keys other than moves ignore it.
+fn read_menu_event(
+    timeout: Option<Duration>,
+    searching: bool,
+    count: &mut i32,
+) -> io::Result<Option<MenuEvent>> {
+    loop {
+        if let Some(timeout) = timeout {
+            if !event::poll(timeout)? {
+                return Ok(None);
+            }
+        }
+        let event = event::read()?;
+        if let Event::Resize(..) = event {
+            return Ok(Some(MenuEvent::Resize));
+        }
+        if let Event::Mouse(mouse) = event {
+            match mouse.kind {
+                MouseEventKind::ScrollUp => return Ok(Some(MenuEvent::Move(-1))),
+                MouseEventKind::ScrollDown => return Ok(Some(MenuEvent::Move(1))),
+                MouseEventKind::Down(MouseButton::Left) => {
+                    return Ok(Some(MenuEvent::Click(mouse.row)))
+                }
+                _ => {}
+            }
+        }
+        if let Event::Key(key) = event {
+            if let (KeyCode::Char(c @ '0'..='9'), false) = (key.code, searching) {
+                // A leading 0 isn't a count.
+                if c != '0' || *count > 0 {
+                    let digit = c.to_digit(10).unwrap_or(0) as i32;
+                    *count = count.saturating_mul(10).saturating_add(digit);
+                    continue;
+                }
+            }
+            let count = std::mem::take(count).max(1);
+            // Raw mode swallows SIGINT, so treat Ctrl-C like any other quit key.
+            if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+                return Ok(Some(MenuEvent::Quit));
+            }
+            if key.modifiers.contains(KeyModifiers::CONTROL) {
+                match key.code {
+                    KeyCode::Char('d') => return Ok(Some(MenuEvent::Page(1))),
+                    KeyCode::Char('u') => return Ok(Some(MenuEvent::Page(-1))),
+                    _ => {}
+                }
+            }
+            if key.modifiers.contains(KeyModifiers::SHIFT) && key.code == KeyCode::Right {
+                return Ok(Some(MenuEvent::ExpandOnly));
+            }
+            if searching {
+                match key.code {
+                    KeyCode::Char(c) => return Ok(Some(MenuEvent::SearchInput(c))),
+                    KeyCode::Backspace => return Ok(Some(MenuEvent::SearchBackspace)),
+                    KeyCode::Enter => return Ok(Some(MenuEvent::SearchDone)),
+                    KeyCode::Esc => return Ok(Some(MenuEvent::SearchCancel)),
+                    // Arrow keys still move through the matches.
+                    _ => {}
+                }
+            }
+            match key.code {
+                KeyCode::Up | KeyCode::Char('k') => return Ok(Some(MenuEvent::Move(-count))),
+                KeyCode::Down | KeyCode::Char('j') => return Ok(Some(MenuEvent::Move(count))),
+                KeyCode::PageDown => return Ok(Some(MenuEvent::Page(2))),
+                KeyCode::PageUp => return Ok(Some(MenuEvent::Page(-2))),
+                KeyCode::Home | KeyCode::Char('g') => return Ok(Some(MenuEvent::Top)),
+                KeyCode::End | KeyCode::Char('G') => return Ok(Some(MenuEvent::Bottom)),
+                KeyCode::Right | KeyCode::Char('l') => return Ok(Some(MenuEvent::Expand)),
+                KeyCode::Char('e') => return Ok(Some(MenuEvent::ExpandFull)),
+                KeyCode::Char('L') => return Ok(Some(MenuEvent::ExpandOnly)),
+                KeyCode::Char('p') | KeyCode::Tab => return Ok(Some(MenuEvent::TogglePreview)),
+                KeyCode::Char('J') => return Ok(Some(MenuEvent::Scroll(1))),
+                KeyCode::Char('K') => return Ok(Some(MenuEvent::Scroll(-1))),
+                KeyCode::Char('s') => return Ok(Some(MenuEvent::ToggleFullSha)),
+                KeyCode::Char('y') => return Ok(Some(MenuEvent::CopySha)),
+                KeyCode::Char('Y') => return Ok(Some(MenuEvent::CopyMessage)),
+                KeyCode::Left | KeyCode::Char('h') => return Ok(Some(MenuEvent::Collapse)),
+                KeyCode::Enter => return Ok(Some(MenuEvent::Confirm)),
+                KeyCode::Char('a') => return Ok(Some(MenuEvent::ConfirmAs(CommitMode::Amend))),
+                KeyCode::Char('r') => return Ok(Some(MenuEvent::ConfirmAs(CommitMode::Reword))),
+                KeyCode::Char('.') => return Ok(Some(MenuEvent::FixupHead)),
+                KeyCode::Char(' ') => return Ok(Some(MenuEvent::ToggleMark)),
+                KeyCode::Char('/') => return Ok(Some(MenuEvent::Search)),
+                KeyCode::Char(':') => return Ok(Some(MenuEvent::JumpToSha)),
+                KeyCode::Char('q') => return Ok(Some(MenuEvent::Quit)),
+                // Clears a kept search first, and quits once there's none.
+                KeyCode::Esc => return Ok(Some(MenuEvent::SearchCancel)),
+                _ => {}
+            }
+        }
+    }
+}
+
+// What the menu was confirmed with.
+enum Pick {
+    Index(usize),
+    // The `.` shortcut: fix up HEAD whatever is selected.
+    Head,
+    // Commits marked with Space, in list order.
+    Marked(Vec<usize>),
+    // The row pinned above the list.
+    Pinned,
+}
+
+// One screen of rendered rows, each holding its text and escape sequences.
+#[derive(Default)]
+struct Frame {
+    lines: Vec<Vec<u8>>,
+}
+
+impl Frame {
+    fn next_line(&mut self) -> &mut Vec<u8> {
+        self.lines.push(Vec::new());
+        self.lines.last_mut().unwrap()
+    }
+
+    // Drops rows from `end` on, for a pane drawn there to have them to itself.
+    fn cut_at(&mut self, end: usize) {
+        self.lines.truncate(end);
+    }
+
+    // Row `y`, padding with blank rows to reach it.
+    fn line_at(&mut self, y: usize) -> &mut Vec<u8> {
+        if self.lines.len() <= y {
+            self.lines.resize(y + 1, Vec::new());
+        }
+        &mut self.lines[y]
+    }
+
+    // Writes the rows that differ from `previous`, clearing whatever each one used to hold past
+    // its new end, and blanks rows the previous frame had but this one doesn't.
+    fn draw(&self, previous: Option<&Frame>, rows: usize, out: &mut impl Write) -> io::Result<()> {
+        let height = self
+            .lines
+            .len()
+            .max(previous.map_or(0, |previous| previous.lines.len()))
+            .min(rows);
+        for y in 0..height {
+            let line = self.lines.get(y);
+            if previous.is_some_and(|previous| previous.lines.get(y) == line) {
+                continue;
+            }
+            queue!(out, cursor::MoveTo(0, y as u16))?;
+            if let Some(line) = line {
+                out.write_all(line)?;
+            }
+            queue!(
+                out,
+                style::SetAttribute(Attribute::Reset),
+                style::ResetColor,
+                terminal::Clear(ClearType::UntilNewLine),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// A `/` search: the commits whose summary, or body when expanded, contains the query,
+// ignoring case.
+#[derive(Default)]
+struct Filter {
+    query: String,
+    // Positions in the full list of the commits that match, in order.
+    matches: Vec<usize>,
+    // How much of the list has been checked, so commits loaded later are checked as they come.
+    scanned: usize,
+}
+
+impl Filter {
+    fn set_query(&mut self, query: String) {
+        self.query = query;
+        self.matches.clear();
+        self.scanned = 0;
+    }
+
+    fn scan(
+        &mut self,
+        repo: &Repository,
+        commits: &CommitList,
+        bodies: &LruCache<Vec<String>>,
+        expanded: &HashSet<usize>,
+    ) -> Result<()> {
+        let needle = self.query.to_lowercase();
+        let contains = |text: &str| text.to_lowercase().contains(&needle);
+        for idx in self.scanned..commits.len() {
+            let row = commits.get(repo, idx)?;
+            // Past the diffstat, which is about the files rather than the message.
+            let in_body = || {
+                expanded.contains(&idx)
+                    && bodies
+                        .get(&row.oid)
+                        .is_some_and(|body| body.iter().skip(1).any(|line| contains(line)))
+            };
+            if contains(&row.summary) || in_body() {
+                self.matches.push(idx);
+            }
+        }
+        self.scanned = commits.len();
+        Ok(())
+    }
+}
+
+// Maps a position in the menu to one in the full list, which differ while a search filters it.
+fn view_at(filter: &Option<Filter>, pos: usize) -> usize {
+    filter.as_ref().map_or(pos, |filter| filter.matches[pos])
+}
+
+fn view_len(filter: &Option<Filter>, commits: &CommitList) -> usize {
+    filter
+        .as_ref()
+        .map_or(commits.len(), |filter| filter.matches.len())
+}
+
+// What main works out about the list before the menu opens.
+struct MenuContext {
+    title: String,
+    // Row selected on open.
+    start: usize,
+    // Shown under the header when the menu opens, e.g. why `start` isn't the row asked for.
+    note: Option<String>,
+    // Commits not yet on the base, for --mark-local.
+    local: Option<HashSet<Oid>>,
+    // The --pin row and the ref it was named by.
+    pinned: Option<(CommitRow, String)>,
+    staged: StagedSummary,
+    // Paths with staged changes, checked against the selected commit's.
+    staged_paths: Vec<PathBuf>,
+    // Ref names to show beside the commits they point at.
+    decorations: HashMap<Oid, Vec<String>>,
+    theme: Theme,
+}
+
+fn run_menu(
+    commits: &mut CommitList,
+    repo: &Repository,
+    mut revwalk: Option<&mut Revwalk>,
+    mut exhausted: bool,
+    ctx: &MenuContext,
+    opts: &Options,
+) -> Result<Option<(Pick, CommitMode)>> {
+    let title = format!("{} [{}]", ctx.title, opts.mode.name());
+    let theme = ctx.theme;
+    let keep = WalkFilter {
+        paths: opts.staged_only.then_some(ctx.staged_paths.as_slice()),
+        no_merges: opts.no_merges,
+        hide_fixups: opts.hide_fixups,
+        limit: opts.limit,
+    };
+    // Opened while the terminal is still untouched, so failing leaves nothing to restore.
+    let mut log = match &opts.log_file {
+        Some(path) => Some(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("--log-file: can't open {}: {e}", path.display()))?,
+        ),
+        None => None,
+    };
+    // Declared before `tty` so the frame still buffered there is flushed before the
+    // guard leaves the alternate screen.
+    let terminal = TerminalGuard::enter()?;
+    // The menu is drawn on the terminal itself, leaving stdout to what --print hands a script.
+    let tty: Box<dyn Write> = match std::fs::OpenOptions::new().write(true).open("/dev/tty") {
+        Ok(tty) => Box::new(tty),
+        Err(_) => Box::new(io::stderr()),
+    };
+    let mut tty = BufWriter::new(tty);
+    let mut selected = ctx.start;
+    let mut scroll = ctx.start;
+    // The pinned row sits outside the list, so it's selected separately; it starts out selected
+    // unless something else was asked for.
+    let mut pin_selected = ctx.pinned.is_some() && ctx.start == 0;
+    let mut expanded: HashSet<usize> = HashSet::new();
+    // Bodies are keyed by Oid, so a late answer from the loader can't land on the wrong row.
+    let cache_entries = opts.cache_entries.unwrap_or(DEFAULT_CACHE_ENTRIES);
+    let mut bodies: LruCache<Vec<String>> = LruCache::new(cache_entries);
+    let mut loading: HashSet<Oid> = HashSet::new();
+    let (body_requests, loaded_bodies) = spawn_body_loader(repo);
+    let mut show_preview = false;
+    let mut full_sha = false;
+    let mut previews: LruCache<Vec<String>> = LruCache::new(cache_entries);
+    let mut previews_loading: HashSet<Oid> = HashSet::new();
+    let (preview_requests, loaded_previews) =
+        spawn_preview_loader(repo, opts.previewer.clone(), !opts.no_color);
+    // The commit the preview last showed, so its scroll resets when the selection moves.
+    let mut preview_of: Option<Oid> = None;
+    let mut preview_scroll = 0usize;
+    // What's on screen, so a redraw only rewrites the rows that changed.
+    let mut previous: Option<Frame> = None;
+    let mut previous_size = (0, 0);
+    // Expanded commits whose body is shown past the line cap.
+    let mut full_bodies: HashSet<usize> = HashSet::new();
+    // How far J/K have scrolled the selected commit's full body, and which commit that was.
+    let mut body_scroll = 0usize;
+    let mut body_scroll_of: Option<usize> = None;
+    let body_cap = opts.max_body_lines.unwrap_or(DEFAULT_BODY_LINES);
+    let mut marked: HashSet<Oid> = HashSet::new();
+    // A vi count being typed, e.g. the 5 of 5j.
+    let mut count = 0;
+    let mut filter: Option<Filter> = None;
+    let mut searching = false;
+    // The sha prefix being typed after `:`.
+    let mut jump: Option<String> = None;
+    // Staged files each commit also changed, by commit, once selected.
+    let mut overlaps: HashMap<Oid, Option<usize>> = HashMap::new();
+    // With --confirm, a pick waiting for y, and the question asked about it.
+    let mut confirming: Option<(MenuEvent, String)> = None;
+    // Set by jumps that may land the selection below the screen.
+    let mut reveal = false;
+    // One-off message shown under the header until the next keypress.
+    let mut toast: Option<String> = ctx.note.clone();
+    let mut mode = opts.mode;
+    // Text that couldn't be copied, printed once the terminal is restored.
+    let mut deferred_output: Vec<String> = Vec::new();
+
+    execute!(tty, terminal::EnterAlternateScreen, cursor::Hide)?;
+    if !opts.no_mouse {
+        execute!(tty, event::EnableMouseCapture)?;
+    }
+
+    let result = loop {
+        if let Some(filter) = &mut filter {
+            filter.scan(repo, commits, &bodies, &expanded)?;
+        }
+        let len = view_len(&filter, commits);
+        // Keep the cursor in bounds when the list shrinks, including down to nothing.
+        selected = selected.min(len.saturating_sub(1));
+        scroll = scroll.min(selected);
+
+        // A toast wins over a --confirm question and the jump and search lines, which win over
+        // what the header would show.
+        let jump_line = jump
+            .as_ref()
+            .map(|prefix| (format!(":{prefix}█"), theme.search));
+        let confirm_line = confirming
+            .as_ref()
+            .map(|(_, question)| (question.clone(), theme.header));
+        let notice = toast
+            .clone()
+            .map(|toast| (toast, theme.notice))
+            .or(confirm_line)
+            .or(jump_line)
+            .or_else(|| {
+                filter.as_ref().map(|filter| {
+                    let query = &filter.query;
+                    if searching {
+                        (format!("/{query}█"), theme.search)
+                    } else {
+                        let count = filter.matches.len();
+                        let more = if exhausted { "" } else { "+" };
+                        let text = format!(
+                            "/{query}  ({count}{more} matching, / to change, Esc to clear)"
+                        );
+                        (text, theme.search)
+                    }
+                })
+            });
+        // A rough hint at how the fixup will fold in: staged files the target also changed
+        // are where a rebase is most likely to need a hand.
+        let target = match &ctx.pinned {
+            Some((row, _)) if pin_selected => Some(row.oid),
+            _ if len == 0 => None,
+            _ => Some(commits.oid(view_at(&filter, selected))),
+        };
+        let staged_line = match target {
+            Some(oid) if !ctx.staged.is_empty() => {
+                let shared = *overlaps.entry(oid).or_insert_with(|| {
+                    repo.find_commit(oid)
+                        .ok()
+                        .and_then(|commit| count_touched(repo, &commit, &ctx.staged_paths).ok())
+                });
+                let sha = &oid.to_string()[..7];
+                match shared {
+                    Some(0) => (
+                        format!("{}  · {sha} changed none of these files", ctx.staged),
+                        theme.clean,
+                    ),
+                    Some(n) => {
+                        let files = if n == 1 { "file" } else { "files" };
+                        let text = format!("{}  · {sha} also changed {n} {files}", ctx.staged);
+                        (text, theme.overlap)
+                    }
+                    None => (ctx.staged.to_string(), theme.dim),
+                }
+            }
+            _ if ctx.staged.is_empty() => (ctx.staged.to_string(), theme.header),
+            _ => (ctx.staged.to_string(), theme.dim),
+        };
+        // Marks can scroll out of sight, so the title keeps count of them.
+        let title = match marked.len() {
+            0 => title.clone(),
+            n => format!("{title} ({n} marked)"),
+        };
+        let quit = if filter.is_some() {
+            "Esc clear search  q quit"
+        } else {
+            "q quit"
+        };
+        let header: Vec<(String, Color)> = match opts.header.unwrap_or_default() {
+            HeaderStyle::Full => vec![
+                (
+                    format!("{title}  ↑/↓ move  →/← expand/collapse  Enter confirm  {quit}"),
+                    theme.header,
+                ),
+                notice.unwrap_or(staged_line),
+            ],
+            HeaderStyle::Short => vec![notice.unwrap_or((title.to_string(), theme.header))],
+            // Notices still need somewhere to go, so they borrow a line while shown.
+            HeaderStyle::None => notice.into_iter().collect(),
+        };
+
+        let (cols, rows) = terminal::size()?;
+        // The preview takes the bottom half of the screen.
+        let preview_height = if show_preview { rows as usize / 2 } else { 0 };
+        let visible_count = (rows as usize)
+            .saturating_sub(header.len() + ctx.pinned.is_some() as usize + preview_height);
+        if reveal {
+            scroll = reveal_from(scroll, selected, visible_count);
+        }
+        if let (Some(window), true) = (opts.window, len > 0) {
+            keep_view_window(commits, repo, &filter, scroll, visible_count, window)?;
+        }
+        // A full body still has to fit on screen next to its summary and the indicators.
+        let full_cap = visible_count.saturating_sub(4).max(1);
+        let selected_abs = (len > 0 && !pin_selected).then(|| view_at(&filter, selected));
+        if body_scroll_of != selected_abs {
+            body_scroll_of = selected_abs;
+            body_scroll = 0;
+        }
+        let skip_for = |idx: usize| {
+            if Some(idx) == selected_abs && full_bodies.contains(&idx) {
+                body_scroll
+            } else {
+                0
+            }
+        };
+        let cap_for = |idx: usize| {
+            if full_bodies.contains(&idx) || body_cap == 0 {
+                full_cap
+            } else {
+                body_cap.min(full_cap)
+            }
+        };
+        let now = unix_now();
+        // Long lines are cut at the screen edge, or with --wrap take as many rows as they need.
+        let fit = |text: &str, width: usize| {
+            if opts.wrap {
+                wrap_to(text, width)
+            } else {
+                vec![truncate_to(text, width)]
+            }
+        };
+        let decoration = |row: &CommitRow| {
+            ctx.decorations
+                .get(&row.oid)
+                .map_or(String::new(), |names| format!(" ({})", names.join(", ")))
+        };
+        // `s` swaps the 7-character sha for the full one.
+        let sha_width = if full_sha { 40 } else { 7 };
+        let sha_of = |row: &CommitRow| {
+            if full_sha {
+                row.oid.to_string()
+            } else {
+                row.short_sha()
+            }
+        };
+        // What's left of a row for the summary once the marker, sha, columns and suffixes are in.
+        let summary_room = |row: &CommitRow| {
+            let used = 2 + row.graph.chars().count() + sha_width;
+            let used = used + row.score_label().len() + display_width(&row.columns(now)) + 1;
+            let suffixes = display_width(&decoration(row)) + row.root_label().len();
+            (cols as usize).saturating_sub(used + suffixes)
+        };
+        let body_room =
+            |row: &CommitRow| (cols as usize).saturating_sub(4 + row.graph.chars().count());
+        let height = |pos: usize| {
+            let idx = view_at(&filter, pos);
+            // A row that can't be read back takes a line rather than stop the layout.
+            let Ok(row) = &commits.get(repo, idx) else {
+                return 1;
+            };
+            let body_lines = match bodies.get(&row.oid) {
+                Some(body) if expanded.contains(&idx) => {
+                    let (shown, hidden) = capped_body(body, cap_for(idx), skip_for(idx));
+                    let room = body_room(row);
+                    let shown: usize = shown.iter().map(|line| fit(line, room).len()).sum();
+                    shown + (hidden > 0) as usize
+                }
+                // Room for the loading placeholder.
+                None if expanded.contains(&idx) => 1,
+                _ => 0,
+            };
+            fit(&row.summary, summary_room(row)).len() + body_lines
+        };
+
+        // After a jump, scroll on until the selection fits, allowing for both indicators.
+        if reveal {
+            while scroll < selected
+                && !collect_visible(len, scroll, visible_count.saturating_sub(2), height)
+                    .contains(&selected)
+            {
+                scroll += 1;
+            }
+            reveal = false;
+        }
+        let (vis_commits, has_more_above, has_more_below) =
+            layout_rows(len, scroll, visible_count, height);
+
+        // Room for every body on screen, or they'd keep pushing each other out and reloading.
+        if cache_entries > 0 {
+            bodies.capacity = cache_entries.max(vis_commits.len());
+        }
+
+        // --- Render ---
+        let mut frame = Frame::default();
+        for (line, color) in &header {
+            queue!(
+                frame.next_line(),
+                style::SetForegroundColor(*color),
+                style::Print(truncate_to(line, cols as usize)),
+                style::ResetColor,
+            )?;
+        }
+
+        // Which screen rows each commit took, for mapping clicks back to them.
+        let pinned_line = ctx.pinned.as_ref().map(|_| frame.lines.len());
+        let mut row_lines: Vec<(Range<usize>, usize)> = Vec::new();
+        if let Some((row, name)) = &ctx.pinned {
+            let line = frame.next_line();
+            let selection = opts.selection_style;
+            let marker = if pin_selected {
+                selection.apply(line)?;
+                selection.marker()
+            } else {
+                ' '
+            };
+            let columns = row.columns(now);
+            let used = 2 + sha_width + display_width(&columns) + 1 + display_width(name) + 3;
+            let summary = truncate_to(&row.summary, (cols as usize).saturating_sub(used));
+            queue!(
+                line,
+                style::Print(format!("{marker} ")),
+                style::SetForegroundColor(theme.sha),
+                style::Print(sha_of(row)),
+                style::SetForegroundColor(theme.dim),
+                style::Print(columns),
+                style::SetForegroundColor(Color::Reset),
+                style::Print(format!(" {summary}")),
+                style::SetForegroundColor(theme.dim),
+                style::Print(format!(" ({name})")),
+                style::SetAttribute(Attribute::Reset),
+                style::ResetColor,
+            )?;
+        }
+
+        if len == 0 {
+            // While typing, q would only join the query.
+            let way_out = if searching {
+                "Backspace to widen the search, or Esc to clear it"
+            } else if filter.is_some() {
+                "Press Esc to clear the search, or q to quit"
+            } else {
+                "Clear the filters to see more, or press q to quit"
+            };
+            let lines = [
+                ("No commits match the current filters", theme.header),
+                (way_out, theme.dim),
+            ];
+            let top = (rows as usize / 2).saturating_sub(1);
+            for (i, (text, color)) in lines.into_iter().enumerate() {
+                let indent = (cols as usize).saturating_sub(display_width(text)) / 2;
+                queue!(
+                    frame.line_at(top + i),
+                    style::Print(" ".repeat(indent)),
+                    style::SetForegroundColor(color),
+                    style::Print(text),
+                    style::ResetColor,
+                )?;
+            }
+        }
+
+        if has_more_above {
+            queue!(
+                frame.next_line(),
+                style::SetForegroundColor(theme.dim),
+                style::Print(format!("  ↑ {scroll} above")),
+                style::ResetColor,
+            )?;
+        }
+
+        let oldest = commits.resident().map(|row| row.time).min().unwrap_or(0);
+        let newest = commits.resident().map(|row| row.time).max().unwrap_or(0);
+        for &pos in &vis_commits {
+            let first_line = frame.lines.len();
+            let abs = view_at(&filter, pos);
+            let row = &commits.get(repo, abs)?;
+            let sha = sha_of(row);
+            let mut summary_lines = fit(&row.summary, summary_room(row)).into_iter();
+            let summary = summary_lines.next().unwrap_or_default();
+            let score = row.score_label();
+            let root = row.root_label();
+            let columns = row.columns(now);
+            let decoration = decoration(row);
+            let sha_color = if opts.color_sha_by_age && !opts.no_color {
+                age_color(row.time, oldest, newest)
+            } else {
+                theme.sha
+            };
+
+            let mark = if marked.contains(&row.oid) { '*' } else { ' ' };
+            let is_local = ctx
+                .local
+                .as_ref()
+                .is_some_and(|local| local.contains(&row.oid));
+            let gutter = if is_local { '│' } else { ' ' };
+            let highlights_in = |text: &str| {
+                filter
+                    .as_ref()
+                    .map_or(Vec::new(), |filter| match_ranges(text, &filter.query))
+            };
+            let highlights = highlights_in(&summary);
+            let line = frame.next_line();
+            if pos == selected && !pin_selected {
+                let selection = opts.selection_style;
+                selection.apply(line)?;
+                queue!(
+                    line,
+                    style::Print(format!("{}{mark}", selection.marker())),
+                    style::SetForegroundColor(theme.graph),
+                    style::Print(&row.graph),
+                    style::SetForegroundColor(sha_color),
+                    style::Print(sha),
+                    style::SetForegroundColor(theme.score),
+                    style::Print(&score),
+                    style::SetForegroundColor(theme.dim),
+                    style::Print(&columns),
+                    style::SetForegroundColor(theme.decoration),
+                    style::Print(&decoration),
+                    style::ResetColor,
+                )?;
+                // ResetColor drops a background selection, so turn the style back on.
+                selection.apply(line)?;
+                queue!(line, style::Print(' '))?;
+                queue_highlighted(line, &summary, &highlights, theme.highlight)?;
+                queue!(
+                    line,
+                    style::SetForegroundColor(theme.dim),
+                    style::Print(root),
+                    style::SetAttribute(Attribute::Reset),
+                    style::ResetColor,
+                )?;
+            } else {
+                queue!(
+                    line,
+                    style::SetForegroundColor(theme.gutter),
+                    style::Print(gutter),
+                    style::ResetColor,
+                    style::Print(mark),
+                    style::SetForegroundColor(theme.graph),
+                    style::Print(&row.graph),
+                    style::SetForegroundColor(sha_color),
+                    style::Print(sha),
+                    style::SetForegroundColor(theme.score),
+                    style::Print(&score),
+                    style::SetForegroundColor(theme.dim),
+                    style::Print(&columns),
+                    style::SetForegroundColor(theme.decoration),
+                    style::Print(&decoration),
+                    style::ResetColor,
+                    style::Print(' '),
+                )?;
+                // Pending fixups are dimmed so they aren't picked as targets by mistake. The
+                // selection is left to its own style.
+                if row.is_fixup() {
+                    queue!(line, style::SetAttribute(Attribute::Dim))?;
+                }
+                queue_highlighted(line, &summary, &highlights, theme.highlight)?;
+                queue!(
+                    line,
+                    style::SetAttribute(Attribute::NormalIntensity),
+                    style::SetForegroundColor(theme.dim),
+                    style::Print(root),
+                    style::ResetColor,
+                )?;
+            }
+            // The rest of a wrapped summary lines up under its first row.
+            let graph_below = graph_below(&row.graph);
+            let indent = " ".repeat(
+                sha_width + score.len() + display_width(&columns) + display_width(&decoration) + 1,
+            );
+            for rest in summary_lines {
+                let line = frame.next_line();
+                queue!(
+                    line,
+                    style::SetForegroundColor(theme.gutter),
+                    style::Print(gutter),
+                    style::ResetColor,
+                )?;
+                queue!(
+                    line,
+                    style::Print(' '),
+                    style::SetForegroundColor(theme.graph),
+                    style::Print(&graph_below),
+                    style::ResetColor,
+                )?;
+                if pos == selected && !pin_selected {
+                    opts.selection_style.apply(line)?;
+                } else if row.is_fixup() {
+                    queue!(line, style::SetAttribute(Attribute::Dim))?;
+                }
+                queue!(line, style::Print(&indent))?;
+                queue_highlighted(line, &rest, &highlights_in(&rest), theme.highlight)?;
+                queue!(
+                    line,
+                    style::SetAttribute(Attribute::Reset),
+                    style::ResetColor
+                )?;
+            }
+
+            if expanded.contains(&abs) {
+                // A body dropped from the cache since it was expanded is loaded again.
+                let oid = commits.oid(abs);
+                if !bodies.contains_key(&oid) && loading.insert(oid) {
+                    body_requests.send(oid)?;
+                }
+                let loading_body = ["loading…".to_string()];
+                let loaded = bodies.get(&row.oid);
+                let body = loaded.map_or(&loading_body[..], Vec::as_slice);
+                let skip = skip_for(abs);
+                let (shown, hidden) = capped_body(body, cap_for(abs), skip);
+                let room = body_room(row);
+                let mut lines: Vec<(String, Color)> = shown
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, line)| {
+                        // The diffstat heads a loaded body and is dimmed like the other stats.
+                        let color = if i == 0 && loaded.is_some() {
+                            theme.dim
+                        } else {
+                            theme.body
+                        };
+                        fit(line, room).into_iter().map(move |line| (line, color))
+                    })
+                    .collect();
+                if hidden > 0 {
+                    // A full body too long for the screen scrolls in place instead.
+                    let more = if !full_bodies.contains(&abs) {
+                        format!("… +{hidden} more (e to show)")
+                    } else {
+                        let skip = skip.min(hidden);
+                        let scroll = if preview_height == 0 && Some(abs) == selected_abs {
+                            " (J/K scroll)"
+                        } else {
+                            ""
+                        };
+                        let last = skip + shown.len();
+                        format!("… lines {}-{last} of {}{scroll}", skip + 1, body.len())
+                    };
+                    lines.push((truncate_to(&more, room), theme.dim));
+                }
+                for (line, color) in lines {
+                    // Carry the gutter and graph down the body so neither breaks off.
+                    queue!(
+                        frame.next_line(),
+                        style::SetForegroundColor(theme.gutter),
+                        style::Print(gutter),
+                        style::Print(' '),
+                        style::SetForegroundColor(theme.graph),
+                        style::Print(&graph_below),
+                        style::SetForegroundColor(color),
+                        style::Print(format!("  {line}")),
+                        style::ResetColor,
+                    )?;
+                }
+            }
+            row_lines.push((first_line..frame.lines.len(), pos));
+        }
+
+        if has_more_below {
+            let last_visible = vis_commits.last().copied().unwrap_or(scroll);
+            let below = len - last_visible - 1;
+            let text = if keep.at_limit(commits) {
+                format!("  ↓ {below} below, --limit reached")
+            } else if exhausted {
+                format!("  ↓ {below} below")
+            } else {
+                format!("  ↓ {below}+ below")
+            };
+            queue!(
+                frame.next_line(),
+                style::SetForegroundColor(theme.dim),
+                style::Print(text),
+                style::ResetColor,
+            )?;
+        }
+
+        // A commit taller than the whole list area is still drawn, so it can run on into the
+        // preview's rows; the preview keeps them, and clicks there don't land on the list.
+        let top = rows as usize - preview_height;
+        if preview_height > 0 {
+            frame.cut_at(top);
+            row_lines.retain_mut(|(lines, _)| {
+                lines.end = lines.end.min(top);
+                lines.start < top
+            });
+        }
+
+        let previewed = match &ctx.pinned {
+            Some((row, _)) if pin_selected => Some(row.oid),
+            _ if len == 0 => None,
+            _ => Some(commits.oid(view_at(&filter, selected))),
+        };
+        if previewed != preview_of {
+            preview_of = previewed;
+            preview_scroll = 0;
+        }
+        if let Some(oid) = previewed.filter(|_| preview_height > 0) {
+            if !previews.contains_key(&oid) && previews_loading.insert(oid) {
+                preview_requests.send((oid, cols))?;
+            }
+            let loading_preview = ["loading…".to_string()];
+            let lines = previews
+                .get(&oid)
+                .map_or(&loading_preview[..], Vec::as_slice);
+            let room = preview_height - 1;
+            preview_scroll = preview_scroll.min(lines.len().saturating_sub(room));
+            let shown = &lines[preview_scroll..lines.len().min(preview_scroll + room)];
+
+            let position = if lines.len() > room {
+                format!(
+                    "{}-{} of {} (J/K scroll) ",
+                    preview_scroll + 1,
+                    preview_scroll + shown.len(),
+                    lines.len()
+                )
+            } else {
+                String::new()
+            };
+            let title = format!("── {} {position}", &oid.to_string()[..7]);
+            let rule = "─".repeat((cols as usize).saturating_sub(display_width(&title)));
+            queue!(
+                frame.line_at(top),
+                style::SetForegroundColor(theme.dim),
+                style::Print(format!("{title}{rule}")),
+                style::ResetColor,
+            )?;
+            for (i, line) in shown.iter().enumerate() {
+                // A previewer brings its own colors; git2's plain patch gets the usual ones.
+                let color = match opts.previewer {
+                    Some(_) => Color::Reset,
+                    None => patch_line_color(line, &theme),
+                };
+                queue!(
+                    frame.line_at(top + 1 + i),
+                    style::SetForegroundColor(color),
+                    style::Print(truncate_ansi(line, cols as usize)),
+                    style::SetAttribute(Attribute::Reset),
+                    style::ResetColor,
+                )?;
+            }
+        }
+
+        // Start from a blank screen whenever the old frame can't be trusted to be on it.
+        if previous_size != (cols, rows) {
+            queue!(tty, terminal::Clear(ClearType::All))?;
+            previous = None;
+            previous_size = (cols, rows);
+        }
+        frame.draw(previous.as_ref(), rows as usize, &mut tty)?;
+        previous = Some(frame);
+        tty.flush()?;
+
+        // --- Events ---
+        // While bodies or previews are loading, wake up regularly to redraw as they arrive.
+        let event = loop {
+            let waiting = !loading.is_empty() || !previews_loading.is_empty();
+            let timeout = waiting.then(|| Duration::from_millis(50));
+            if let Some(event) = read_menu_event(
+                timeout,
+                searching || jump.is_some() || confirming.is_some(),
+                &mut count,
+            )? {
+                break Some(event);
+            }
+            let bodies_arrived = receive_bodies(&loaded_bodies, &mut bodies, &mut loading);
+            if receive_bodies(&loaded_previews, &mut previews, &mut previews_loading)
+                || bodies_arrived
+            {
+                break None;
+            }
+        };
+        receive_bodies(&loaded_bodies, &mut bodies, &mut loading);
+        receive_bodies(&loaded_previews, &mut previews, &mut previews_loading);
+        if let Some(log) = &mut log {
+            // The state is what the key was pressed against, before it's handled.
+            let event = event.as_ref().map_or("null".to_string(), |event| {
+                json_string(&format!("{event:?}"))
+            });
+            let _ = writeln!(
+                log,
+                "{{\"event\":{event},\"cols\":{cols},\"rows\":{rows},\"selected\":{selected},\"scroll\":{scroll}}}"
+            );
+        }
+        let Some(event) = event else {
+            // The selection's body arriving grows it just as expanding does.
+            reveal |= len > 0 && expanded.contains(&view_at(&filter, selected));
+            continue;
+        };
+        toast = None;
+        let event = match event {
+            MenuEvent::ConfirmAs(kind) => {
+                mode = kind;
+                MenuEvent::Confirm
+            }
+            MenuEvent::Confirm | MenuEvent::FixupHead => {
+                mode = opts.mode;
+                event
+            }
+            event => event,
+        };
+        // --confirm holds a pick back until it's answered: y lets it through, n or Esc goes
+        // back to the menu as it was, and other keys leave the question up.
+        let event = match confirming.take() {
+            Some((held, question)) => match event {
+                MenuEvent::SearchInput('y' | 'Y') => held,
+                MenuEvent::SearchInput('n' | 'N' | 'q') | MenuEvent::SearchCancel => continue,
+                MenuEvent::Quit => break None,
+                _ => {
+                    confirming = Some((held, question));
+                    continue;
+                }
+            },
+            None if opts.confirm
+                && (matches!(event, MenuEvent::FixupHead)
+                    || matches!(event, MenuEvent::Confirm) && (len > 0 || pin_selected)) =>
+            {
+                let target = if matches!(event, MenuEvent::FixupHead) {
+                    "HEAD".to_string()
+                } else if let (true, Some((row, _))) = (pin_selected, &ctx.pinned) {
+                    format!("{} {}", row.short_sha(), row.summary)
+                } else if !marked.is_empty() {
+                    format!("the {} marked commits", marked.len())
+                } else {
+                    let row = &commits.get(repo, view_at(&filter, selected))?;
+                    format!("{} {}", row.short_sha(), row.summary)
+                };
+                let question = format!("Create the {} for {target}? (y/n)", mode.name());
+                confirming = Some((event, question));
+                continue;
+            }
+            None => event,
+        };
+        match event {
+            MenuEvent::TogglePreview => {
+                show_preview = !show_preview;
+                continue;
+            }
+            MenuEvent::ToggleFullSha => {
+                full_sha = !full_sha;
+                // Summaries lose room, and with --wrap rows can grow past the selection.
+                reveal = true;
+                continue;
+            }
+            // A click anywhere on a commit, body included, selects it. Clicks elsewhere do
+            // nothing.
+            MenuEvent::Click(y) => {
+                let y = y as usize;
+                if pinned_line == Some(y) {
+                    pin_selected = true;
+                } else if let Some((_, pos)) =
+                    row_lines.iter().find(|(lines, _)| lines.contains(&y))
+                {
+                    pin_selected = false;
+                    selected = *pos;
+                }
+                continue;
+            }
+            MenuEvent::Resize => {
+                // A taller screen can show more of the history than has been walked.
+                let (_, rows) = terminal::size()?;
+                if let Some(revwalk) = revwalk.as_deref_mut() {
+                    while !exhausted && view_len(&filter, commits) < scroll + rows as usize {
+                        let batch = (rows as usize).max(1);
+                        exhausted = fetch_more(repo, revwalk, batch, commits, keep)?;
+                        if let Some(filter) = &mut filter {
+                            filter.scan(repo, commits, &bodies, &expanded)?;
+                        }
+                    }
+                }
+                // The layout is recomputed against the new size on the way round, and the
+                // selection scrolled back into view if the screen got shorter.
+                reveal = true;
+                continue;
+            }
+            MenuEvent::Scroll(delta) if preview_height == 0 => {
+                if let Some(abs) = selected_abs.filter(|abs| full_bodies.contains(abs)) {
+                    let lines = bodies.get(&commits.oid(abs)).map_or(0, Vec::len);
+                    body_scroll = body_scroll
+                        .saturating_add_signed(delta as isize)
+                        .min(lines.saturating_sub(cap_for(abs)));
+                }
+                continue;
+            }
+            MenuEvent::Scroll(delta) => {
+                // Clamped against the preview's length when it's next drawn.
+                preview_scroll = preview_scroll.saturating_add_signed(delta as isize);
+                continue;
+            }
+            _ => {}
+        }
+        if let Some(prefix) = &mut jump {
+            match event {
+                MenuEvent::SearchInput(c) if c.is_ascii_hexdigit() => {
+                    prefix.push(c.to_ascii_lowercase())
+                }
+                MenuEvent::SearchBackspace => {
+                    prefix.pop();
+                }
+                MenuEvent::SearchInput(_) => continue,
+                MenuEvent::SearchDone | MenuEvent::SearchCancel => {
+                    jump = None;
+                    continue;
+                }
+                // Arrow keys still move.
+                _ => {}
+            }
+            if let MenuEvent::SearchInput(_) | MenuEvent::SearchBackspace = event {
+                let prefix = prefix.clone();
+                if prefix.is_empty() {
+                    continue;
+                }
+                // Look through what's loaded, then keep walking until a sha matches.
+                let mut from = 0;
+                let found = loop {
+                    let len = view_len(&filter, commits);
+                    let found = (from..len).find(|&pos| {
+                        commits
+                            .oid(view_at(&filter, pos))
+                            .to_string()
+                            .starts_with(&prefix)
+                    });
+                    let Some(revwalk) = revwalk.as_deref_mut() else {
+                        break found;
+                    };
+                    if found.is_some() || exhausted {
+                        break found;
+                    }
+                    from = len;
+                    exhausted = fetch_more(repo, revwalk, visible_count.max(1), commits, keep)?;
+                    if let Some(filter) = &mut filter {
+                        filter.scan(repo, commits, &bodies, &expanded)?;
+                    }
+                };
+                match found {
+                    Some(pos) => {
+                        pin_selected = false;
+                        selected = pos;
+                        reveal = true;
+                    }
+                    None => toast = Some(format!(":{prefix}  no commit matches")),
+                }
+                continue;
+            }
+        }
+        match event {
+            MenuEvent::JumpToSha => {
+                jump = Some(String::new());
+                continue;
+            }
+            MenuEvent::Search => {
+                searching = true;
+                filter.get_or_insert_with(Filter::default);
+                continue;
+            }
+            MenuEvent::SearchInput(_) | MenuEvent::SearchBackspace => {
+                let filter = filter.get_or_insert_with(Filter::default);
+                let mut query = filter.query.clone();
+                match event {
+                    MenuEvent::SearchInput(c) => query.push(c),
+                    _ => {
+                        query.pop();
+                    }
+                }
+                filter.set_query(query);
+                // A changed query starts again from the first match.
+                selected = 0;
+                scroll = 0;
+                continue;
+            }
+            MenuEvent::SearchDone => {
+                searching = false;
+                if filter
+                    .as_ref()
+                    .is_some_and(|filter| filter.query.is_empty())
+                {
+                    filter = None;
+                }
+                continue;
+            }
+            MenuEvent::SearchCancel if filter.is_none() => break None,
+            MenuEvent::SearchCancel => {
+                searching = false;
+                // Stay on the same commit once the whole list is back.
+                if len > 0 {
+                    selected = view_at(&filter, selected);
+                    scroll = selected;
+                }
+                filter = None;
+                continue;
+            }
+            _ => {}
+        }
+        if pin_selected {
+            match event {
+                MenuEvent::Move(delta) if delta > 0 && len > 0 => {
+                    pin_selected = false;
+                    selected = scroll;
+                }
+                MenuEvent::Confirm => break Some(Pick::Pinned),
+                MenuEvent::FixupHead => break Some(Pick::Head),
+                MenuEvent::Quit => break None,
+                _ => {}
+            }
+            continue;
+        }
+        match event {
+            MenuEvent::Move(delta) if delta < 0 && selected == 0 && ctx.pinned.is_some() => {
+                pin_selected = true;
+            }
+            MenuEvent::Move(delta) => {
+                let next = (selected as i32).saturating_add(delta);
+                let mut len = len;
+                // Past the last match so far, or further than a counted move finds loaded, keep
+                // walking until there's a row to land on.
+                if let Some(revwalk) = revwalk.as_deref_mut() {
+                    // A screen too short for any rows would otherwise ask for none each time round.
+                    while next >= len as i32 && !exhausted {
+                        let batch = visible_count.max(1);
+                        exhausted = fetch_more(repo, revwalk, batch, commits, keep)?;
+                        if let Some(filter) = &mut filter {
+                            filter.scan(repo, commits, &bodies, &expanded)?;
+                        }
+                        len = view_len(&filter, commits);
+                    }
+                }
+                if next >= len as i32 && keep.at_limit(commits) {
+                    toast = Some(limit_note(commits.len()));
+                }
+                // A count running off either end stops at the first or last row, as in vi.
+                let next = next.clamp(0, (len as i32 - 1).max(0)) as usize;
+                if len > 0 && next != selected {
+                    selected = next;
+                    if selected < scroll {
+                        scroll = selected;
+                    } else if !vis_commits.contains(&selected) {
+                        if delta == 1 {
+                            scroll += 1;
+                            if !has_more_above && scroll > 0 {
+                                scroll += 1;
+                            }
+                        } else {
+                            reveal = true;
+                        }
+                    }
+                    if selected + visible_count >= len {
+                        if let Some(revwalk) = revwalk.as_deref_mut() {
+                            let batch = visible_count.max(1);
+                            exhausted = fetch_more(repo, revwalk, batch, commits, keep)?;
+                        }
+                    }
+                }
+            }
+            MenuEvent::Page(_) | MenuEvent::Bottom if len > 0 => {
+                let target = match event {
+                    MenuEvent::Page(halves) => {
+                        let step = (visible_count * halves.unsigned_abs() as usize / 2).max(1);
+                        if halves > 0 {
+                            selected + step
+                        } else {
+                            selected.saturating_sub(step)
+                        }
+                    }
+                    _ => usize::MAX,
+                };
+                let mut len = len;
+                if let Some(revwalk) = revwalk.as_deref_mut() {
+                    // Load a screen past the target, as single steps do.
+                    while target.saturating_add(visible_count) >= len && !exhausted {
+                        exhausted = fetch_more(repo, revwalk, visible_count.max(1), commits, keep)?;
+                        if let Some(filter) = &mut filter {
+                            filter.scan(repo, commits, &bodies, &expanded)?;
+                        }
+                        len = view_len(&filter, commits);
+                    }
+                }
+                // Pages keep the selection where it was on screen; the bottom row goes last.
+                let offset = match event {
+                    MenuEvent::Bottom => visible_count,
+                    _ => selected - scroll,
+                };
+                selected = target.min(len - 1);
+                scroll = selected.saturating_sub(offset);
+                reveal = true;
+                if target >= len && keep.at_limit(commits) {
+                    toast = Some(limit_note(commits.len()));
+                }
+            }
+            MenuEvent::Page(_) | MenuEvent::Bottom => {}
+            MenuEvent::Top => {
+                selected = 0;
+                scroll = 0;
+            }
+            MenuEvent::Expand | MenuEvent::ExpandFull | MenuEvent::ExpandOnly if len > 0 => {
+                let abs = view_at(&filter, selected);
+                if matches!(event, MenuEvent::ExpandOnly) {
+                    expanded.retain(|&idx| idx == abs);
+                    full_bodies.retain(|&idx| idx == abs);
+                }
+                let oid = commits.oid(abs);
+                if !bodies.contains_key(&oid) && loading.insert(oid) {
+                    body_requests.send(oid)?;
+                }
+                if matches!(event, MenuEvent::ExpandFull) {
+                    full_bodies.insert(abs);
+                }
+                expanded.insert(abs);
+                // Growing the bottom row could push it under the indicator.
+                reveal = true;
+            }
+            MenuEvent::Expand | MenuEvent::ExpandFull | MenuEvent::ExpandOnly => {}
+            MenuEvent::Collapse if len > 0 => {
+                let abs = view_at(&filter, selected);
+                expanded.remove(&abs);
+                full_bodies.remove(&abs);
+            }
+            MenuEvent::Collapse => {}
+            MenuEvent::CopyMessage if len > 0 => {
+                let row = &commits.get(repo, view_at(&filter, selected))?;
+                if let Some(msg) = commit_message(repo, row.oid) {
+                    toast = Some(match clipboard::copy(&msg) {
+                        Ok(()) => {
+                            format!("Copied the message of {} to the clipboard", row.short_sha())
+                        }
+                        Err(e) => {
+                            deferred_output.push(msg);
+                            format!(
+                                "Clipboard unavailable ({e}); the message will be printed on exit"
+                            )
+                        }
+                    });
+                }
+            }
+            MenuEvent::CopySha if len > 0 => {
+                let row = &commits.get(repo, view_at(&filter, selected))?;
+                toast = Some(match clipboard::copy(&row.oid.to_string()) {
+                    Ok(()) => format!("Copied {} to the clipboard", row.short_sha()),
+                    Err(e) => {
+                        deferred_output.push(row.oid.to_string());
+                        format!("Clipboard unavailable ({e}); the sha will be printed on exit")
+                    }
+                });
+            }
+            MenuEvent::CopyMessage | MenuEvent::CopySha => {}
+            MenuEvent::Confirm if len == 0 => {
+                // Nothing to pick; ring the bell instead.
+                execute!(tty, style::Print("\x07"))?;
+            }
+            MenuEvent::Confirm if !marked.is_empty() => {
+                let indices = (0..commits.len())
+                    .filter(|&idx| marked.contains(&commits.oid(idx)))
+                    .collect();
+                break Some(Pick::Marked(indices));
+            }
+            MenuEvent::Confirm => break Some(Pick::Index(view_at(&filter, selected))),
+            MenuEvent::ToggleMark if len > 0 => {
+                let oid = commits.oid(view_at(&filter, selected));
+                if !marked.remove(&oid) {
+                    marked.insert(oid);
+                }
+            }
+            MenuEvent::ToggleMark => {}
+            MenuEvent::TogglePreview
+            | MenuEvent::ToggleFullSha
+            | MenuEvent::Scroll(_)
+            | MenuEvent::Search
+            | MenuEvent::JumpToSha
+            | MenuEvent::SearchInput(_)
+            | MenuEvent::SearchBackspace
+            | MenuEvent::SearchDone
+            | MenuEvent::SearchCancel
+            | MenuEvent::ConfirmAs(_)
+            | MenuEvent::Resize
+            | MenuEvent::Click(_) => {}
+            MenuEvent::FixupHead => break Some(Pick::Head),
+            MenuEvent::Quit => break None,
+        }
+    };
+
+    drop(tty);
+    drop(terminal);
+
+    let len = view_len(&filter, commits);
+    if opts.resume && len > 0 && !pin_selected {
+        save_position(repo, commits.oid(view_at(&filter, selected.min(len - 1))));
+    }
+
+    // On stderr, so they stay out of what --print hands to a script.
+    for text in deferred_output {
+        eprintln!("{text}");
+    }
+
+    Ok(result.map(|pick| (pick, mode)))
+}
+
+// Holds the terminal in raw mode on the alternate screen for the menu, and puts it back when
+// dropped, whether the menu returns or unwinds from a panic.
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn enter() -> io::Result<Self> {
+        // Restore before the default hook prints, so the message lands on the normal screen.
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            default_hook(info);
+        }));
+        terminal::enable_raw_mode()?;
+        MENU_ACTIVE.store(true, Ordering::SeqCst);
+        Ok(TerminalGuard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+        MENU_ACTIVE.store(false, Ordering::SeqCst);
+        // Later panics happen outside the menu and need no restoring. The hook can't be
+        // swapped while unwinding, but then the process is on its way out anyway.
+        if !thread::panicking() {
+            let _ = panic::take_hook();
+        }
+    }
+}
+
+// Leaves the alternate screen, mouse capture and raw mode. Opens its own handle on /dev/tty
+// since whoever calls this may be interrupting a write through the menu's.
+fn restore_terminal() {
+    if let Ok(mut tty) = std::fs::OpenOptions::new().write(true).open("/dev/tty") {
+        let _ = execute!(
+            tty,
+            event::DisableMouseCapture,
+            terminal::LeaveAlternateScreen,
+            cursor::Show
+        );
+    }
+    let _ = terminal::disable_raw_mode();
+}
+
+// Whether the menu has the terminal, and so needs it put back on the way out.
+static MENU_ACTIVE: AtomicBool = AtomicBool::new(false);
+// Whether a child such as the editor or `git rebase -i` is in the foreground.
+static CHILD_RUNNING: AtomicBool = AtomicBool::new(false);
+
+// Exits on an interrupt or termination, putting the terminal back to normal if the menu had
+// it. While a child runs the signals are its to handle, as they are for a shell's foreground
+// job; the child sees them too, being in the same process group.
+fn install_signal_handler() {
+    let Ok(mut signals) = Signals::new([SIGINT, SIGTERM]) else {
+        return;
+    };
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if CHILD_RUNNING.load(Ordering::SeqCst) {
+                continue;
+            }
+            if MENU_ACTIVE.load(Ordering::SeqCst) {
+                restore_terminal();
+            }
+            std::process::exit(128 + signal);
+        }
+    });
+}
+
+// Runs `cmd` in the foreground and waits for it, with our signal handling stood down.
+fn run_child(cmd: &mut Command) -> io::Result<ExitStatus> {
+    CHILD_RUNNING.store(true, Ordering::SeqCst);
+    let status = cmd.status();
+    CHILD_RUNNING.store(false, Ordering::SeqCst);
+    status
+}
+
+// Autosquash can still fold a fixup into the root commit, but only with `--root`.
+fn note_root_target(row: &CommitRow) {
+    if row.is_root {
+        eprintln!(
+            "Note: {} is the root commit; there is nothing before it to rebase onto, \
+             so fold the fixup with `git rebase -i --autosquash --root`.",
+            row.short_sha()
+        );
+    }
+}
+
+fn create_fixup_commit(sha: &str, mode: CommitMode, opts: &Options) -> Result<()> {
+    let flag = match mode {
+        CommitMode::Fixup | CommitMode::Squash => format!("--{}", mode.name()),
+        CommitMode::Amend | CommitMode::Reword => {
+            if let Some((major, minor)) = git_version() {
+                if (major, minor) < (2, 32) {
+                    eprintln!(
+                        "git {major}.{minor} can't create {} fixups; they need git 2.32 or newer",
+                        mode.name()
+                    );
+                    std::process::exit(1);
+                }
+            }
+            format!("--fixup={}:{sha}", mode.name())
+        }
+    };
+    let mut cmd = Command::new("git");
+    cmd.args(["commit", &flag]);
+    if let CommitMode::Fixup | CommitMode::Squash = mode {
+        cmd.arg(sha);
+    }
+    if opts.allow_empty {
+        cmd.arg("--allow-empty");
+    }
+    // Git only consults the template for commits that open an editor, such as `squash!`.
+    if let Some(template) = &opts.message_template {
+        cmd.arg("--template").arg(template);
+    }
+    let status =
+        run_child(&mut cmd).map_err(|e| format!("failed to run git commit {flag}: {e}"))?;
+
+    if !status.success() {
+        eprintln!("git commit {flag} failed");
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// The installed git's major and minor version, if `git --version` can be read.
+fn git_version() -> Option<(u32, u32)> {
+    let output = Command::new("git").arg("--version").output().ok()?;
+    let text = String::from_utf8(output.stdout).ok()?;
+    // e.g. `git version 2.39.2` or `git version 2.39.3 (Apple Git-146)`
+    let mut parts = text.split_whitespace().nth(2)?.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+// Opens the rebase todo list from the target's parent, with the fixups moved into place only
+// when `autosquash` is set. The menu has given the terminal back by the time this runs.
+fn start_interactive_rebase(row: &CommitRow, autosquash: bool) -> Result<()> {
+    let mut cmd = Command::new("git");
+    let autosquash = if autosquash {
+        "--autosquash"
+    } else {
+        "--no-autosquash"
+    };
+    cmd.args(["rebase", "-i", autosquash, "--autostash"]);
+    if row.is_root {
+        cmd.arg("--root");
+    } else {
+        cmd.arg(format!("{}^", row.oid));
+    }
+    let status = run_child(&mut cmd).map_err(|e| format!("failed to run git rebase -i: {e}"))?;
+
+    if !status.success() {
+        eprintln!(
+            "git rebase -i stopped; finish with `git rebase --continue` or `git rebase --abort`"
+        );
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// Where --resume keeps the last selection, one `<sha> <git dir>` line per repository.
+fn positions_file() -> Option<PathBuf> {
+    let cache = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache.join("git-fixup-menu").join("positions"))
+}
+
+fn load_position(repo: &Repository) -> Option<Oid> {
+    let text = std::fs::read_to_string(positions_file()?).ok()?;
+    let key = repo.path().to_string_lossy();
+    text.lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|&(_, path)| path == key)
+        .and_then(|(sha, _)| Oid::from_str(sha).ok())
+}
+
+// Best effort: a position that can't be saved only means starting at the top next time.
+fn save_position(repo: &Repository, oid: Oid) {
+    let Some(file) = positions_file() else {
+        return;
+    };
+    let key = repo.path().to_string_lossy();
+    let old = std::fs::read_to_string(&file).unwrap_or_default();
+    let mut text: String = old
+        .lines()
+        .filter(|line| line.split_once(' ').is_none_or(|(_, path)| path != key))
+        .map(|line| format!("{line}\n"))
+        .collect();
+    text.push_str(&format!("{oid} {key}\n"));
+    if let Some(dir) = file.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let _ = std::fs::write(&file, text);
+}
+
+// Asks a yes/no question on the terminal, which still works when stdin is a pipe.
+fn confirm_on_tty(prompt: &str) -> bool {
+    eprint!("{prompt} [y/N] ");
+    let mut answer = String::new();
+    let read = std::fs::File::open("/dev/tty")
+        .and_then(|tty| io::BufRead::read_line(&mut io::BufReader::new(tty), &mut answer));
+    read.is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+fn is_ancestor_of_head(repo: &Repository, oid: Oid) -> bool {
+    let Some(head) = repo.head().ok().and_then(|head| head.target()) else {
+        return false;
+    };
+    head == oid || repo.graph_descendant_of(head, oid).unwrap_or(false)
+}
+
+// Files staged against HEAD, by kind of change.
+#[derive(Default)]
+struct StagedSummary {
+    added: usize,
+    modified: usize,
+    deleted: usize,
+    renamed: usize,
+}
+
+impl StagedSummary {
+    fn is_empty(&self) -> bool {
+        self.added + self.modified + self.deleted + self.renamed == 0
+    }
+}
+
+impl std::fmt::Display for StagedSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "Nothing staged");
+        }
+        let parts: Vec<String> = [
+            (self.added, "added"),
+            (self.modified, "modified"),
+            (self.deleted, "deleted"),
+            (self.renamed, "renamed"),
+        ]
+        .into_iter()
+        .filter(|&(count, _)| count > 0)
+        .map(|(count, kind)| format!("{count} {kind}"))
+        .collect();
+        write!(f, "Staged: {}", parts.join(", "))
+    }
+}
+
+fn staged_summary(repo: &Repository) -> StagedSummary {
+    let summarize = || -> Result<StagedSummary, git2::Error> {
+        // Before the first commit everything in the index counts as added.
+        let head_tree = repo.head().and_then(|head| head.peel_to_tree()).ok();
+        let mut index = repo.index()?;
+        // Reload in case `git add` changed the index behind our back.
+        index.read(true)?;
+        let mut diff = repo.diff_tree_to_index(head_tree.as_ref(), Some(&index), None)?;
+        diff.find_similar(None)?;
+
+        let mut summary = StagedSummary::default();
+        for delta in diff.deltas() {
+            match delta.status() {
+                Delta::Added | Delta::Copied => summary.added += 1,
+                Delta::Deleted => summary.deleted += 1,
+                Delta::Renamed => summary.renamed += 1,
+                _ => summary.modified += 1,
+            }
+        }
+        Ok(summary)
+    };
+    summarize().unwrap_or_default()
+}
+
+// Asks before targeting a commit that autosquash couldn't fold the fixup into.
+fn confirm_target(repo: &Repository, row: &CommitRow) {
+    if !is_ancestor_of_head(repo, row.oid) {
+        eprintln!(
+            "Target {} isn't an ancestor of HEAD; autosquash won't fold it.",
+            row.short_sha()
+        );
+        if !confirm_on_tty("Create the fixup anyway?") {
+            std::process::exit(1);
+        }
+    }
+}
+
+// Walks through `git add -p` once per marked target, committing a fixup for whatever was staged.
+fn apply_marked(repo: &Repository, rows: &[&CommitRow], opts: &Options) -> Result<()> {
+    if opts.print {
+        for row in rows {
+            println!("{}", row.oid);
+        }
+        return Ok(());
+    }
+    for row in rows {
+        confirm_target(repo, row);
+    }
+    if !staged_summary(repo).is_empty() {
+        eprintln!(
+            "Changes already staged go into the fixup for {}.",
+            rows[0].short_sha()
+        );
+    }
+    for row in rows {
+        eprintln!(
+            "\nStage the changes for {} {}",
+            row.short_sha(),
+            row.summary
+        );
+        let status = run_child(Command::new("git").args(["add", "-p"]))
+            .map_err(|e| format!("failed to run git add -p: {e}"))?;
+        if !status.success() {
+            eprintln!("git add -p failed");
+            std::process::exit(1);
+        }
+        if staged_summary(repo).is_empty() {
+            eprintln!("Nothing staged; skipping {}", row.short_sha());
+            continue;
+        }
+        create_fixup_commit(&row.oid.to_string(), opts.mode, opts)?;
+    }
+    if (opts.interactive_rebase || opts.autosquash_rebase) && !rows.is_empty() {
+        start_interactive_rebase(&rebase_base(repo, rows)?, opts.autosquash_rebase)?;
+    }
+    Ok(())
+}
+
+// Where a rebase over several targets starts so each of them is in the todo list: the target
+// all the others descend from, or else the commit their histories meet at. Committer times
+// can't say, since rebases and cherry-picks reorder them.
+fn rebase_base(repo: &Repository, rows: &[&CommitRow]) -> Result<CommitRow> {
+    let below_all = |row: &&&CommitRow| {
+        rows.iter().all(|other| {
+            other.oid == row.oid
+                || repo
+                    .graph_descendant_of(other.oid, row.oid)
+                    .unwrap_or(false)
+        })
+    };
+    if let Some(row) = rows.iter().find(below_all) {
+        return Ok((*row).clone());
+    }
+    let oids: Vec<Oid> = rows.iter().map(|row| row.oid).collect();
+    let base = repo
+        .merge_base_many(&oids)
+        .map_err(|_| "The marked commits share no history to rebase from".to_string())?;
+    Ok(CommitRow::new(&repo.find_commit(base)?))
+}
+
+fn apply_selection(repo: &Repository, row: &CommitRow, opts: &Options) -> Result<()> {
+    if opts.print {
+        println!("{}", row.oid);
+        return Ok(());
+    }
+    // Checked again here since the index may have changed while the menu was open. A reword
+    // only touches the message, so it needs nothing staged.
+    let needs_changes = !opts.allow_empty && !matches!(opts.mode, CommitMode::Reword);
+    if needs_changes && staged_summary(repo).is_empty() {
+        eprintln!(
+            "Nothing staged; stage the changes for {} first.",
+            row.short_sha()
+        );
+        std::process::exit(1);
+    }
+    confirm_target(repo, row);
+    if !opts.interactive_rebase && !opts.autosquash_rebase {
+        note_root_target(row);
+    }
+    create_fixup_commit(&row.oid.to_string(), opts.mode, opts)?;
+    if opts.interactive_rebase || opts.autosquash_rebase {
+        start_interactive_rebase(row, opts.autosquash_rebase)?;
+    }
+    Ok(())
+}
+
+// Names the sequencer operation a fixup commit would get tangled up in, if any.
+fn operation_in_progress(repo: &Repository) -> Option<&'static str> {
+    match repo.state() {
+        RepositoryState::Clean => None,
+        RepositoryState::Merge => Some("merge"),
+        RepositoryState::Revert | RepositoryState::RevertSequence => Some("revert"),
+        RepositoryState::CherryPick | RepositoryState::CherryPickSequence => Some("cherry-pick"),
+        RepositoryState::Bisect => Some("bisect"),
+        RepositoryState::Rebase
+        | RepositoryState::RebaseInteractive
+        | RepositoryState::RebaseMerge
+        | RepositoryState::ApplyMailbox
+        | RepositoryState::ApplyMailboxOrRebase => Some("rebase"),
+    }
+}
+
+// Turns a path given relative to the current directory into one relative to the work tree.
+fn repo_relative_path(repo: &Repository, path: &str) -> Option<PathBuf> {
+    let workdir = repo.workdir()?.canonicalize().ok()?;
+    let absolute = std::env::current_dir().ok()?.join(path);
+    // The file may be gone from the work tree, so canonicalize only its directory.
+    let absolute = match (absolute.parent(), absolute.file_name()) {
+        (Some(dir), Some(name)) => dir.canonicalize().ok()?.join(name),
+        _ => absolute,
+    };
+    absolute.strip_prefix(&workdir).ok().map(Path::to_path_buf)
+}
+
+// Finds the newest commit in range that changed `path`. A merge only counts when its result
+// differs from every parent, as with `git log -- <path>`. Renames count as changes, so the
+// newest touch is found even when the file moved.
+fn last_commit_touching(repo: &Repository, opts: &Options, path: &str) -> Result<Option<Oid>> {
+    let Some(relative) = repo_relative_path(repo, path) else {
+        return Ok(None);
+    };
+    let entry_id = |commit: &Commit| {
+        commit
+            .tree()
+            .ok()
+            .and_then(|tree| tree.get_path(&relative).ok())
+            .map(|entry| entry.id())
+    };
+    let (walk, _) = history_walk(repo, opts)?;
+    Ok(walk.filter_map(|oid| oid.ok()).find(|&oid| {
+        let Ok(commit) = repo.find_commit(oid) else {
+            return false;
+        };
+        let id = entry_id(&commit);
+        if commit.parent_count() == 0 {
+            return id.is_some();
+        }
+        commit.parents().all(|parent| entry_id(&parent) != id)
+    }))
+}
+
+// Sets up the walk over the configured range. Also returns the range's label, if limited.
+fn history_walk<'r>(repo: &'r Repository, opts: &Options) -> Result<(Revwalk<'r>, Option<String>)> {
+    let mut walk = repo.revwalk()?;
+    // Sorting by time or topology makes libgit2 walk the whole history before handing out the
+    // first commit, which takes seconds on a big repository. Its default order, git log's, is
+    // read as it goes. --graph needs every child before its parents for the lanes.
+    if opts.graph || opts.topo_order {
+        walk.set_sorting(Sort::TIME | Sort::TOPOLOGICAL)?;
+    }
+    if opts.first_parent {
+        walk.simplify_first_parent()?;
+    }
+    match &opts.limit_to_branch {
+        Some(name) => {
+            let range = branch_range(repo, name.as_deref(), opts.branch_base.as_deref())?;
+            walk.push(range.tip)?;
+            walk.hide(range.merge_base)?;
+            Ok((walk, Some(range.label)))
+        }
+        None => {
+            walk.push_head()?;
+            let Some(since) = &opts.since else {
+                return Ok((walk, None));
+            };
+            let base = repo
+                .revparse_single(since)
+                .and_then(|obj| obj.peel_to_commit())
+                .map_err(|_| format!("--since: `{since}` doesn't name a commit"))?;
+            walk.hide(base.id())?;
+            Ok((walk, Some(format!("{since}..HEAD"))))
+        }
+    }
+}
+
+// Formats seconds since the epoch as a UTC `YYYY-MM-DD` date.
+fn format_date(secs: i64) -> String {
+    // Civil-from-days, after Howard Hinnant's date algorithms.
+    let z = secs.div_euclid(86_400) + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Summarises the walk: commit and author counts, date span, and pending fixups/squashes.
+fn print_stats(repo: &Repository, walk: Revwalk, json: bool) {
+    let mut commits = 0usize;
+    let mut authors = HashSet::new();
+    let mut span: Option<(i64, i64)> = None;
+    let (mut fixups, mut squashes) = (0usize, 0usize);
+    for oid in walk.filter_map(Result::ok) {
+        let Ok(commit) = repo.find_commit(oid) else {
+            continue;
+        };
+        commits += 1;
+        let author = commit.author();
+        authors.insert(author.email().or(author.name()).unwrap_or("").to_string());
+        let time = commit.time().seconds();
+        span = Some(span.map_or((time, time), |(lo, hi)| (lo.min(time), hi.max(time))));
+        let summary = commit.summary().unwrap_or("");
+        if summary.starts_with("fixup!") || summary.starts_with("amend!") {
+            fixups += 1;
+        } else if summary.starts_with("squash!") {
+            squashes += 1;
+        }
+    }
+
+    let (first, last) = span.map_or((String::new(), String::new()), |(lo, hi)| {
+        (format_date(lo), format_date(hi))
+    });
+    if json {
+        println!(
+            "{{\"commits\":{commits},\"authors\":{},\"first\":{},\"last\":{},\"fixups\":{fixups},\"squashes\":{squashes}}}",
+            authors.len(),
+            json_string(&first),
+            json_string(&last),
+        );
+    } else {
+        println!("commits:  {commits}");
+        println!("authors:  {}", authors.len());
+        if span.is_some() {
+            println!("span:     {first} .. {last}");
+        }
+        println!("fixup!:   {fixups}");
+        println!("squash!:  {squashes}");
+    }
+}
+
+fn main() {
+    // Errors are printed as their message alone, after the menu has restored the terminal.
+    if let Err(e) = run() {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    // --help answers before there's any need for a repository.
+    let mut opts = parse_args();
+    let Ok(repo) = Repository::discover(".") else {
+        return Err("Not a valid git repo.".into());
+    };
+    apply_config(&repo, &mut opts);
+    // A background color is still a color; reverse video marks the selection without one.
+    if opts.no_color && matches!(opts.selection_style, SelectionStyle::Background(_)) {
+        opts.selection_style = SelectionStyle::Reverse;
+    }
+    install_signal_handler();
+
+    // Also true on an orphan branch in a repo that has commits elsewhere.
+    if repo
+        .head()
+        .is_err_and(|e| e.code() == ErrorCode::UnbornBranch || e.code() == ErrorCode::NotFound)
+    {
+        eprintln!("No commits yet.");
+        return Ok(());
+    }
+
+    if opts.stats.is_some() {
+        let (walk, _) = history_walk(&repo, &opts)?;
+        print_stats(&repo, walk, opts.stats == Some(StatsFormat::Json));
+        return Ok(());
+    }
+    if opts.count_only {
+        let (walk, _) = history_walk(&repo, &opts)?;
+        println!("{}", walk.count());
+        return Ok(());
+    }
+
+    if let (Some(operation), false) = (operation_in_progress(&repo), opts.print) {
+        return Err(format!(
+            "A {operation} is in progress; finish or abort it before creating a fixup."
+        )
+        .into());
+    }
+
+    // Better to find out now than after picking a commit. Marking commits stages as it goes and
+    // `r` rewords without changes, so the menu can still be opened.
+    if !opts.allow_empty && !opts.print && staged_summary(&repo).is_empty() {
+        eprintln!("Nothing staged; the fixup would be empty (pass --allow-empty to allow that).");
+        if opts.yes || !confirm_on_tty("Open the menu anyway?") {
+            std::process::exit(1);
+        }
+    }
+
+    let mut preselect = None;
+    if let Some(path) = &opts.target_file {
+        let target = last_commit_touching(&repo, &opts, path)?
+            .ok_or_else(|| format!("No commit in range touched {path}."))?;
+        if opts.yes {
+            let commit = repo.find_commit(target)?;
+            return apply_selection(&repo, &CommitRow::new(&commit), &opts);
+        }
+        preselect = Some(target);
+    }
+    // Only a commit still in HEAD's history, so a stale position doesn't walk everything.
+    if preselect.is_none() && opts.resume {
+        preselect = load_position(&repo).filter(|&oid| is_ancestor_of_head(&repo, oid));
+    }
+
+    let local = match &opts.mark_local {
+        Some(base) => {
+            Some(local_commits(&repo, base.as_deref()).map_err(|e| format!("--mark-local: {e}"))?)
+        }
+        None => None,
+    };
+
+    let staged_paths = staged_paths(&repo)?;
+    if opts.staged_only && staged_paths.is_empty() {
+        return Err("Nothing staged, so --staged-only has no files to look for.".into());
+    }
+    let keep = WalkFilter {
+        paths: opts.staged_only.then_some(staged_paths.as_slice()),
+        no_merges: opts.no_merges,
+        hide_fixups: opts.hide_fixups,
+        limit: opts.limit,
+    };
+
+    let mut start = 0;
+    let mut note = None;
+    let mut revwalk = None;
+    let mut title = if repo.head_detached().unwrap_or(false) {
+        "Select a commit (detached HEAD)".to_string()
+    } else {
+        "Select a commit".to_string()
+    };
+    let (mut commits, exhausted) = if let Some(base) = &opts.filter_fixup_target {
+        let commits = rank_fixup_targets(&repo, base.as_deref())
+            .map_err(|e| format!("Failed to rank fixup targets: {}", e.message()))?;
+        if commits.is_empty() {
+            eprintln!("No commits own the lines touched by the staged changes.");
+            return Ok(());
+        }
+        (CommitList::new(commits), true)
+    } else if opts.stdin {
+        let commits = read_stdin_commits(&repo)?;
+        if commits.is_empty() {
+            eprintln!("No commits read from stdin.");
+            return Ok(());
+        }
+        (CommitList::new(commits), true)
+    } else {
+        let (mut walk, range) = history_walk(&repo, &opts)?;
+        if let Some(range) = &range {
+            title = format!("Select a commit in {range}");
+        }
+
+        let (_, rows) = terminal::size()?;
+        // Just a screenful; scrolling walks the rest as it's needed. Some ptys report no height
+        // at all, and a batch of none would never get anywhere.
+        let initial = (rows as usize).max(1);
+        let mut commits = CommitList::new(Vec::new());
+        if opts.graph {
+            commits.graph = Some(Graph::default());
+        }
+        let mut exhausted = fetch_more(&repo, &mut walk, initial, &mut commits, keep)?;
+
+        if commits.is_empty() {
+            match range {
+                Some(range) => eprintln!("No commits in range {range}."),
+                None => eprintln!("No commits found."),
+            }
+            return Ok(());
+        }
+        if let Some(target) = preselect {
+            while !exhausted && commits.position(target).is_none() {
+                exhausted = fetch_more(&repo, &mut walk, initial, &mut commits, keep)?;
+            }
+            match commits.position(target) {
+                Some(pos) => start = pos,
+                // Filtered out, or past --limit: say so rather than quietly start at the top.
+                None => {
+                    let sha = &target.to_string()[..7];
+                    note = Some(format!(
+                        "{sha} isn't in the list, so the menu starts at the top"
+                    ));
+                }
+            }
+        }
+        revwalk = Some(walk);
+        (commits, exhausted)
+    };
+
+    let pinned = match &opts.pin {
+        Some(name) => {
+            let name = name.as_deref().unwrap_or("HEAD");
+            let commit = repo
+                .revparse_single(name)
+                .and_then(|obj| obj.peel_to_commit())
+                .map_err(|_| format!("--pin: `{name}` doesn't name a commit"))?;
+            Some((CommitRow::new(&commit), name.to_string()))
+        }
+        None => None,
+    };
+    let ctx = MenuContext {
+        title,
+        start,
+        note,
+        local,
+        pinned,
+        staged: staged_summary(&repo),
+        staged_paths,
+        decorations: ref_decorations(&repo),
+        theme: if opts.no_color {
+            Theme::plain()
+        } else {
+            Theme::default()
+        },
+    };
+
+    let Some((pick, mode)) = run_menu(
+        &mut commits,
+        &repo,
+        revwalk.as_mut(),
+        exhausted,
+        &ctx,
+        &opts,
+    )?
+    else {
+        return Ok(());
+    };
+    // The menu may have been confirmed as a different kind of fixup.
+    opts.mode = mode;
+    match pick {
+        Pick::Index(index) => apply_selection(&repo, &commits.get(&repo, index)?, &opts),
+        Pick::Marked(indices) => {
+            let rows = indices.iter().map(|&idx| commits.get(&repo, idx));
+            let rows = rows.collect::<Result<Vec<_>>>()?;
+            let rows: Vec<&CommitRow> = rows.iter().collect();
+            apply_marked(&repo, &rows, &opts)
+        }
+        Pick::Pinned => match &ctx.pinned {
+            Some((row, _)) => apply_selection(&repo, row, &opts),
+            None => Ok(()),
+        },
+        Pick::Head => {
+            let head = repo.head()?.peel_to_commit()?;
+            apply_selection(&repo, &CommitRow::new(&head), &opts)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Signature;
+
+    // A fresh repository under the temp dir, removed by the caller.
+    fn scratch_repo(name: &str) -> (PathBuf, Repository) {
+        let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        (dir, repo)
+    }
+
+    fn commit(repo: &Repository, summary: &str, parents: &[Oid], update_head: bool) -> Oid {
+        let sig = Signature::now("Test", "test@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let parents: Vec<Commit> = parents
+            .iter()
+            .map(|p| repo.find_commit(*p).unwrap())
+            .collect();
+        let parents: Vec<&Commit> = parents.iter().collect();
+        let head = update_head.then_some("HEAD");
+        repo.commit(head, &sig, &sig, summary, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn first_parent_walk_skips_merged_in_commits() {
+        let (dir, repo) = scratch_repo("fixup-menu-first-parent");
+        let root = commit(&repo, "root", &[], true);
+        let side = commit(&repo, "side", &[root], false);
+        let main = commit(&repo, "main", &[root], true);
+        let merge = commit(&repo, "merge", &[main, side], true);
+        commit(&repo, "top", &[merge], true);
+
+        let list = |opts: &Options, n: usize| {
+            let (mut walk, _) = history_walk(&repo, opts).unwrap();
+            let mut commits = CommitList::new(Vec::new());
+            let mut batches = 0;
+            while !fetch_more(&repo, &mut walk, n, &mut commits, WalkFilter::default()).unwrap() {
+                batches += 1;
+            }
+            let summaries: Vec<_> = (0..commits.len())
+                .map(|i| commits.get(&repo, i).unwrap().summary)
+                .collect();
+            (summaries, batches)
+        };
+        let first_parent = Options {
+            first_parent: true,
+            ..Options::default()
+        };
+
+        assert_eq!(list(&Options::default(), 10).0.len(), 5);
+        assert_eq!(list(&first_parent, 10).0, ["top", "merge", "main", "root"]);
+        // Batches still page through the simplified walk.
+        assert_eq!(list(&first_parent, 1), (list(&first_parent, 10).0, 4));
+        // And the range still stops at --since.
+        let since_main = Options {
+            first_parent: true,
+            since: Some(main.to_string()),
+            ..Options::default()
+        };
+        assert_eq!(list(&since_main, 10).0, ["top", "merge"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn far_jump_with_a_small_window_keeps_the_revealed_rows_loaded() {
+        let (dir, repo) = scratch_repo("fixup-menu-window-jump");
+        let mut parent = commit(&repo, "c0", &[], true);
+        for i in 1..40 {
+            parent = commit(&repo, &format!("c{i}"), &[parent], true);
+        }
+        let (mut walk, _) = history_walk(&repo, &Options::default()).unwrap();
+        let mut commits = CommitList::new(Vec::new());
+        while !fetch_more(&repo, &mut walk, 10, &mut commits, WalkFilter::default()).unwrap() {}
+        commits.keep_window(&repo, 0, 10).unwrap();
+
+        // A jump from the top to near the bottom of a 5-row screen.
+        let (selected, visible_count) = (35, 5);
+        let scroll = reveal_from(0, selected, visible_count);
+        assert_eq!(scroll, 30);
+        keep_view_window(&mut commits, &repo, &None, scroll, visible_count, 10).unwrap();
+        let resident: Vec<Oid> = commits.resident().map(|row| row.oid).collect();
+        for idx in scroll..commits.len() {
+            assert!(
+                resident.contains(&commits.oid(idx)),
+                "row {idx} was dropped"
+            );
+        }
+        // Jumping back up starts the view at the selection.
+        assert_eq!(reveal_from(30, 3, visible_count), 3);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // The graph column for each commit from HEAD, walked as --graph walks them.
+    fn graph_rows(repo: &Repository) -> Vec<String> {
+        let mut walk = repo.revwalk().unwrap();
+        walk.set_sorting(Sort::TOPOLOGICAL).unwrap();
+        walk.push_head().unwrap();
+        let mut graph = Graph::default();
+        walk.map(|oid| graph.row(&repo.find_commit(oid.unwrap()).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn graph_of_linear_history_is_one_lane() {
+        let (dir, repo) = scratch_repo("fixup-menu-graph-linear");
+        let root = commit(&repo, "root", &[], true);
+        let next = commit(&repo, "next", &[root], true);
+        commit(&repo, "top", &[next], true);
+
+        assert_eq!(graph_rows(&repo), ["* ", "* ", "* "]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn graph_opens_a_lane_for_a_merge_and_closes_it_at_the_base() {
+        let (dir, repo) = scratch_repo("fixup-menu-graph-merge");
+        let root = commit(&repo, "root", &[], true);
+        let side = commit(&repo, "side", &[root], false);
+        let main = commit(&repo, "main", &[root], true);
+        let merge = commit(&repo, "merge", &[main, side], true);
+        commit(&repo, "top", &[merge], true);
+
+        assert_eq!(graph_rows(&repo), ["* ", "* \\ ", "| * ", "* | ", "* / "]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn match_ranges_folds_case_like_the_search() {
+        assert!(match_ranges("", "fix").is_empty());
+        assert!(match_ranges("Fix the parser", "").is_empty());
+        assert_eq!(match_ranges("ABab", "ab"), [0..2, 2..4]);
+        // İ lowercases to two chars, so it only matches a query that has both.
+        assert_eq!(match_ranges("İstanbul", "İS"), [Range { start: 0, end: 3 }]);
+        assert!(match_ranges("İstanbul", "is").is_empty());
+    }
+
+    #[test]
+    fn truncate_to_measures_wide_characters() {
+        assert_eq!(truncate_to("abcdef", 4), "abc…");
+        assert_eq!(truncate_to("abcd", 4), "abcd");
+        assert_eq!(truncate_to("日本", 4), "日本");
+        assert_eq!(truncate_to("日本語のテキスト", 5), "日本…");
+        assert_eq!(truncate_to("fix 🐛 now", 7), "fix 🐛…");
+        assert_eq!(truncate_to("fix 🐛 now", 6), "fix …");
+    }
+
+    #[test]
+    fn truncate_ansi_keeps_escapes_at_the_cut() {
+        assert_eq!(truncate_ansi("\x1b[31mabc\x1b[0m", 2), "\x1b[31mab\x1b[0m");
+        assert_eq!(truncate_ansi("ab\x1b[1mcd", 2), "ab\x1b[1m");
+        // A wide character that doesn't fit is left off whole.
+        assert_eq!(truncate_ansi("日本語", 3), "日");
+    }
+
+    #[test]
+    fn wrap_to_splits_words_longer_than_the_width() {
+        assert_eq!(wrap_to("a abcdefghij b", 4), ["a", "abcd", "efgh", "ij b"]);
+        assert_eq!(wrap_to("日本語", 4), ["日本", "語"]);
+        assert_eq!(wrap_to("", 4), [""]);
+    }
+
+    #[test]
+    fn lru_cache_evicts_the_least_recently_read() {
+        let oid = |n: u8| Oid::from_bytes(&[n; 20]).unwrap();
+        let mut cache = LruCache::new(2);
+        cache.insert(oid(1), "one");
+        cache.insert(oid(2), "two");
+        // Reading 1 makes 2 the oldest, so 2 goes when 3 arrives.
+        assert_eq!(cache.get(&oid(1)), Some(&"one"));
+        cache.insert(oid(3), "three");
+        assert!(cache.contains_key(&oid(1)));
+        assert!(!cache.contains_key(&oid(2)));
+        assert!(cache.contains_key(&oid(3)));
+        assert_eq!(cache.entries.len(), 2);
+
+        // A capacity of 0 never evicts.
+        let mut unbounded = LruCache::new(0);
+        for n in 0..10 {
+            unbounded.insert(oid(n), n);
+        }
+        assert_eq!(unbounded.entries.len(), 10);
+    }
+
+    #[test]
+    fn rebase_base_follows_ancestry() {
+        let (dir, repo) = scratch_repo("fixup-menu-rebase-base");
+        let root = commit(&repo, "root", &[], true);
+        let a = commit(&repo, "a", &[root], true);
+        let b = commit(&repo, "b", &[a], true);
+        let side = commit(&repo, "side", &[root], false);
+        let row = |oid| CommitRow::new(&repo.find_commit(oid).unwrap());
+        let (a, b, side) = (row(a), row(b), row(side));
+
+        // Whichever order they were marked in.
+        assert_eq!(rebase_base(&repo, &[&b, &a]).unwrap().oid, a.oid);
+        assert_eq!(rebase_base(&repo, &[&a, &b]).unwrap().oid, a.oid);
+        // Targets on diverging lines start from where they meet.
+        assert_eq!(rebase_base(&repo, &[&b, &side]).unwrap().oid, root);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn capped_body_scrolls_within_the_body() {
+        let body: Vec<String> = (1..=5).map(|n| format!("line {n}")).collect();
+        assert_eq!(capped_body(&body, 10, 3), (&body[..], 0));
+        assert_eq!(capped_body(&body, 2, 0), (&body[..2], 3));
+        assert_eq!(capped_body(&body, 2, 2), (&body[2..4], 3));
+        // Scrolling stops with the last line at the bottom.
+        assert_eq!(capped_body(&body, 2, 9), (&body[3..], 3));
+    }
+
+    #[test]
+    fn preview_keeps_its_rows_from_an_overlong_body() {
+        // A 5-line list area over a preview, with the first commit's body taller than that.
+        let height = |idx: usize| if idx == 0 { 8 } else { 1 };
+        let (vis, above, below) = layout_rows(10, 0, 5, height);
+        assert_eq!((vis.as_slice(), above, below), (&[0][..], false, true));
+        let mut frame = Frame::default();
+        for line in 0..height(0) + below as usize {
+            frame.next_line().extend(format!("list {line}").bytes());
+        }
+        frame.cut_at(5);
+        frame.line_at(5).extend(b"preview");
+        assert_eq!(frame.lines.len(), 6);
+        assert_eq!(frame.lines[4], b"list 4");
+        assert_eq!(frame.lines[5], b"preview");
+    }
+
+    #[test]
+    fn walk_flags_are_refused_without_a_walk() {
+        let graph = Options {
+            graph: true,
+            ..Options::default()
+        };
+        assert_eq!(walk_only_flag(&graph), None);
+        let stdin = Options {
+            stdin: true,
+            ..graph
+        };
+        assert_eq!(walk_only_flag(&stdin), Some("--graph"));
+        let ranked = Options {
+            filter_fixup_target: Some(None),
+            limit: Some(5),
+            ..Options::default()
+        };
+        assert_eq!(walk_only_flag(&ranked), Some("--limit"));
+    }
+
+    #[test]
+    fn fixup_summaries_are_recognised() {
+        assert!(is_fixup_summary("fixup! Add the parser"));
+        assert!(is_fixup_summary("fixup! fixup! Add the parser"));
+        assert!(is_fixup_summary("squash! Add the parser"));
+        assert!(is_fixup_summary("amend! Add the parser"));
+        assert!(!is_fixup_summary("Add the parser"));
+        assert!(!is_fixup_summary("Revert \"fixup! Add the parser\""));
+    }
+
+    #[test]
+    fn collect_visible_with_no_room_is_empty() {
+        assert!(collect_visible(5, 0, 0, |_| 1).is_empty());
+    }
+
+    #[test]
+    fn collect_visible_keeps_a_commit_taller_than_the_limit() {
+        // An expanded body longer than the screen still shows its commit.
+        assert_eq!(
+            collect_visible(3, 0, 4, |idx| if idx == 0 { 9 } else { 1 }),
+            [0]
+        );
+        assert_eq!(
+            collect_visible(3, 1, 4, |idx| if idx == 1 { 9 } else { 1 }),
+            [1]
+        );
+    }
+
+    #[test]
+    fn collect_visible_fills_the_limit_exactly() {
+        let heights = [1, 3, 1, 1];
+        assert_eq!(collect_visible(4, 0, 5, |idx| heights[idx]), [0, 1, 2]);
+        assert_eq!(collect_visible(4, 0, 4, |idx| heights[idx]), [0, 1]);
+        assert_eq!(collect_visible(4, 1, 5, |idx| heights[idx]), [1, 2, 3]);
+    }
+
+    #[test]
+    fn layout_leaves_room_for_the_indicators() {
+        // A one-row screen gives its line to the row rather than either indicator.
+        assert_eq!(layout_rows(10, 0, 1, |_| 1), (vec![0], false, false));
+        assert_eq!(layout_rows(10, 5, 1, |_| 1), (vec![5], false, false));
+        // Two rows: scrolled down, "above" takes one, and "below" can't take the other.
+        assert_eq!(layout_rows(10, 0, 2, |_| 1), (vec![0], false, true));
+        assert_eq!(layout_rows(10, 5, 2, |_| 1), (vec![5], true, false));
+        // Three rows: both indicators and the row between them.
+        assert_eq!(layout_rows(10, 0, 3, |_| 1), (vec![0, 1], false, true));
+        assert_eq!(layout_rows(10, 5, 3, |_| 1), (vec![5], true, true));
+        // Reaching the end needs no "below".
+        assert_eq!(layout_rows(3, 0, 3, |_| 1), (vec![0, 1, 2], false, false));
+        assert_eq!(layout_rows(10, 8, 3, |_| 1), (vec![8, 9], true, false));
+    }
+
+    #[test]
+    fn no_merges_leaves_merge_commits_out() {
+        let (dir, repo) = scratch_repo("fixup-menu-no-merges");
+        let root = commit(&repo, "root", &[], true);
+        let side = commit(&repo, "side", &[root], false);
+        let main = commit(&repo, "main", &[root], true);
+        let merge = commit(&repo, "merge", &[main, side], true);
+        commit(&repo, "top", &[merge], true);
+
+        let list = |keep: WalkFilter, n: usize| {
+            let mut walk = repo.revwalk().unwrap();
+            walk.set_sorting(Sort::TOPOLOGICAL).unwrap();
+            walk.push_head().unwrap();
+            let mut commits = CommitList::new(Vec::new());
+            fetch_more(&repo, &mut walk, n, &mut commits, keep).unwrap();
+            (0..commits.len())
+                .map(|i| commits.get(&repo, i).unwrap().summary)
+                .collect::<Vec<_>>()
+        };
+        let no_merges = WalkFilter {
+            no_merges: true,
+            ..WalkFilter::default()
+        };
+
+        assert_eq!(list(WalkFilter::default(), 10).len(), 5);
+        let kept = list(no_merges, 10);
+        assert_eq!(kept.len(), 4);
+        assert!(!kept.contains(&"merge".to_string()));
+        // The count is of kept commits, so skipping the merge doesn't cut the batch short.
+        assert_eq!(list(no_merges, 2).len(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn limit_caps_loaded_commits() {
+        let (dir, repo) = scratch_repo("fixup-menu-limit");
+        let mut parent = commit(&repo, "c0", &[], true);
+        for i in 1..5 {
+            parent = commit(&repo, &format!("c{i}"), &[parent], true);
+        }
+        let limited = WalkFilter {
+            limit: Some(3),
+            ..WalkFilter::default()
+        };
+
+        let mut walk = repo.revwalk().unwrap();
+        walk.push_head().unwrap();
+        let mut commits = CommitList::new(Vec::new());
+        // Two batches of 2 stop at the limit, and report the walk as done there.
+        assert!(!fetch_more(&repo, &mut walk, 2, &mut commits, limited).unwrap());
+        assert!(fetch_more(&repo, &mut walk, 2, &mut commits, limited).unwrap());
+        assert_eq!(commits.len(), 3);
+        assert!(limited.at_limit(&commits));
 
-    if let Some(index) = run_menu(&mut commits, &repo, &mut revwalk) {
-        let sha = &commits[index][..7];
-        create_fixup_commit(sha);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
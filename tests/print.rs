@@ -0,0 +1,96 @@
+// Runs the menu on a pseudo-terminal with stdout piped, as `sha=$(git fixup-menu --print)`
+// does, to check the menu stays on the terminal and only the sha reaches stdout.
+
+use git2::{Repository, Signature};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::FromRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+fn open_pty() -> (File, File) {
+    let (mut master, mut slave) = (0, 0);
+    let size = libc::winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let opened = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            &size,
+        )
+    };
+    assert_eq!(opened, 0, "openpty failed");
+    unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) }
+}
+
+#[test]
+fn print_writes_only_the_sha_to_stdout() {
+    let dir = std::env::temp_dir().join(format!("fixup-menu-print-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let repo = Repository::init(&dir).unwrap();
+    let sig = Signature::now("Test", "test@example.com").unwrap();
+    let tree = repo
+        .find_tree(repo.index().unwrap().write_tree().unwrap())
+        .unwrap();
+    let head = repo
+        .commit(Some("HEAD"), &sig, &sig, "only commit", &tree, &[])
+        .unwrap();
+
+    let (mut master, slave) = open_pty();
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_git-fixup-menu"));
+    cmd.arg("--print")
+        .current_dir(&dir)
+        .env("TERM", "xterm")
+        .stdin(slave.try_clone().unwrap())
+        .stderr(slave.try_clone().unwrap())
+        .stdout(Stdio::piped());
+    // Make the pty the child's controlling terminal, so it's what /dev/tty opens.
+    unsafe {
+        cmd.pre_exec(|| {
+            libc::setsid();
+            libc::ioctl(0, libc::TIOCSCTTY as _, 0);
+            Ok(())
+        });
+    }
+    let child = cmd.spawn().unwrap();
+    drop(slave);
+
+    // Keep the terminal drained, and note when the menu has drawn its commit.
+    let (drawn, menu_drawn) = mpsc::channel();
+    let mut reader = master.try_clone().unwrap();
+    thread::spawn(move || {
+        let mut screen = Vec::new();
+        let mut buf = [0; 4096];
+        while let Ok(n) = reader.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            screen.extend_from_slice(&buf[..n]);
+            if String::from_utf8_lossy(&screen).contains("only commit") {
+                let _ = drawn.send(());
+            }
+        }
+    });
+    menu_drawn
+        .recv_timeout(Duration::from_secs(10))
+        .expect("the menu was never drawn on the terminal");
+    master.write_all(b"\r").unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{head}\n")
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}